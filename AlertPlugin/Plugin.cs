@@ -27,6 +27,12 @@ public class VpilotAlert : IPlugin
             baseUrl = File.ReadAllText("Plugins\\baseUrl.txt");
         }
 
+        if (File.Exists("Plugins\\apiKey.txt"))
+        {
+            string apiKey = File.ReadAllText("Plugins\\apiKey.txt").Trim();
+            client.DefaultRequestHeaders.Authorization = new System.Net.Http.Headers.AuthenticationHeaderValue("Bearer", apiKey);
+        }
+
         vPilot.PostDebugMessage($"Using baseUrl: {baseUrl}");
 
         periodicTimer = new Timer(PeriodicCheck, null, TimeSpan.Zero, TimeSpan.FromSeconds(5));
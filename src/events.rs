@@ -0,0 +1,96 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Local;
+use eyre::{Context, Result};
+use serde::Serialize;
+use tracing::error;
+
+use crate::{route::TrackPoint, state::NotificationType};
+
+#[derive(Clone, Default)]
+pub struct EventLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    AlarmStarted {
+        message: &'a str,
+        #[serde(rename = "type")]
+        _type: &'a NotificationType,
+    },
+    AlarmStopped {
+        reason: &'a str,
+    },
+    CrashCheck {
+        callsign: &'a str,
+        check: &'a str,
+        tripped: bool,
+    },
+    RouteRecomputed {
+        route: &'a str,
+        waypoints: usize,
+    },
+    WaypointPassed {
+        waypoint: &'a str,
+    },
+    ConnectionChanged {
+        connected: bool,
+    },
+    Position {
+        callsign: &'a str,
+        #[serde(flatten)]
+        point: &'a TrackPoint,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+impl EventLog {
+    pub fn open(path: Option<&str>) -> Result<EventLog> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context("Could not open event log")?,
+            ),
+            None => None,
+        };
+        Ok(EventLog {
+            file: file.map(|f| Arc::new(Mutex::new(f))),
+        })
+    }
+
+    pub fn record(&self, event: Event) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let record = Record {
+            timestamp: Local::now().to_rfc3339(),
+            event,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to serialize event: {err}");
+                return;
+            }
+        };
+        if let Err(err) = writeln!(file.lock().unwrap(), "{line}") {
+            error!("Failed to write event log: {err}");
+        }
+    }
+}
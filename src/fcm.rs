@@ -1,20 +1,47 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use async_trait::async_trait;
+use clap::ValueEnum;
 use eyre::{bail, Context, Result};
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::{spawn, sync::Mutex, time::sleep};
+use tokio::{
+    fs::{read_to_string, write},
+    spawn,
+    sync::Mutex,
+    time::sleep,
+};
 use tracing::error;
 
+use crate::{notify::Notifier, state::NotificationType};
+
 #[derive(Clone, Deserialize)]
 pub struct GoogleServices {
+    project_id: String,
     private_key: String,
     client_email: String,
     #[serde(skip)]
+    oauth_cache_path: PathBuf,
+    #[serde(skip)]
+    client: Client,
+    #[serde(skip)]
     data: Arc<Mutex<ServiceData>>,
+    #[serde(skip)]
+    display_platforms: Vec<FcmPlatform>,
+}
+
+/// Platforms that can be sent a visible notification along with the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FcmPlatform {
+    /// Replaces the app's own alarm handling, which needs data-only messages
+    Android,
+    /// iOS only shows data-only messages while the app is running
+    Apns,
+    Webpush,
 }
 
 #[derive(Serialize)]
@@ -29,6 +56,7 @@ struct Claims {
 struct ServiceData {
     token_refreshed_at: Instant,
     oauth_token: TokenResponse,
+    recipient_token: String,
 }
 
 impl Default for ServiceData {
@@ -36,45 +64,93 @@ impl Default for ServiceData {
         Self {
             token_refreshed_at: Instant::now(),
             oauth_token: TokenResponse::default(),
+            recipient_token: String::new(),
         }
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    fetched_at: u64,
+    token: TokenResponse,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 impl GoogleServices {
+    pub fn with_oauth_cache_path(mut self, path: PathBuf) -> Self {
+        self.oauth_cache_path = path;
+        self
+    }
+
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Have the OS render these platforms' notifications, even when the app is not running.
+    pub fn with_display_platforms(mut self, platforms: Vec<FcmPlatform>) -> Self {
+        self.display_platforms = platforms;
+        self
+    }
+
     pub async fn login(&self) -> Result<()> {
         let mut token_state = self.data.lock().await;
-        token_state.oauth_token = self.fetch_oauth_token().await?;
-        token_state.token_refreshed_at = Instant::now();
+        match load_cached_token(&self.oauth_cache_path).await {
+            Some((refreshed_at, token)) => {
+                token_state.oauth_token = token;
+                token_state.token_refreshed_at = refreshed_at;
+            }
+            None => {
+                token_state.oauth_token = self.fetch_oauth_token().await?;
+                token_state.token_refreshed_at = Instant::now();
+            }
+        }
         drop(token_state);
 
         let services = self.clone();
         spawn(async move {
+            let mut failures = 0;
             loop {
-                let mut token_state = services.data.lock().await;
-                let can_refresh = Duration::from_secs(token_state.oauth_token.expires_in)
-                    - Duration::from_secs(60);
-                if token_state.token_refreshed_at.elapsed() > can_refresh {
-                    match services.fetch_oauth_token().await {
-                        Ok(token) => {
-                            token_state.oauth_token = token;
-                            token_state.token_refreshed_at = Instant::now();
-                            continue;
-                        }
-                        Err(e) => error!("Failed to refresh OAuth token: {}", e),
+                let token_state = services.data.lock().await;
+                let until_refresh = refresh_interval(token_state.oauth_token.expires_in)
+                    .saturating_sub(token_state.token_refreshed_at.elapsed());
+                drop(token_state);
+                if !until_refresh.is_zero() {
+                    sleep(until_refresh + Duration::from_secs(1)).await;
+                    continue;
+                }
+
+                match services.fetch_oauth_token().await {
+                    Ok(token) => {
+                        failures = 0;
+                        let mut token_state = services.data.lock().await;
+                        token_state.oauth_token = token;
+                        token_state.token_refreshed_at = Instant::now();
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        let backoff = Duration::from_secs(1 << failures.min(8));
+                        error!(
+                            "Failed to refresh OAuth token, retrying in {}s: {}",
+                            backoff.as_secs(),
+                            e
+                        );
+                        sleep(backoff).await;
                     }
                 }
-                drop(token_state);
-                sleep(can_refresh + Duration::from_secs(1)).await;
             }
         });
         Ok(())
     }
 
     async fn fetch_oauth_token(&self) -> Result<TokenResponse> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let now = unix_now();
 
         let claims = Claims {
             iss: self.client_email.clone(),
@@ -90,49 +166,73 @@ impl GoogleServices {
         let jwt = encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)
             .context("Failed to encode JWT")?;
 
-        let client = Client::new();
         let params = [
             ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
             ("assertion", &jwt),
         ];
 
-        let response = client
+        let response = self
+            .client
             .post("https://oauth2.googleapis.com/token")
             .form(&params)
             .send()
             .await
             .context("Send OAuth token request")?;
 
-        let token_response = response.json().await.context("Parse token response")?;
+        let token_response: TokenResponse =
+            response.json().await.context("Parse token response")?;
+        if let Err(err) = store_cached_token(&self.oauth_cache_path, now, &token_response).await {
+            error!("Failed to cache OAuth token: {}", err);
+        }
         Ok(token_response)
     }
 
+    /// Writes the current OAuth token to the cache so a restart does not have to fetch a new one.
+    pub async fn save_oauth_token(&self) -> Result<()> {
+        let token_state = self.data.lock().await;
+        let fetched_at =
+            unix_now().saturating_sub(token_state.token_refreshed_at.elapsed().as_secs());
+        store_cached_token(&self.oauth_cache_path, fetched_at, &token_state.oauth_token).await
+    }
+
     async fn token(&self) -> TokenResponse {
         let token_state = self.data.lock().await;
         token_state.oauth_token.clone()
     }
 
+    pub async fn set_recipient_token(&self, token: String) {
+        self.data.lock().await.recipient_token = token;
+    }
+
     pub async fn send_fcm_message(
         &self,
-        recipient_token: &str,
-        data: serde_json::Value,
+        body: &str,
+        _type: &NotificationType,
+        trigger_alarm: bool,
+        priority: Priority,
     ) -> Result<()> {
-        let client = Client::new();
-        let message = json!({
+        let recipient_token = self.data.lock().await.recipient_token.clone();
+        let mut message = json!({
             "message": {
                 "token": recipient_token,
-                "data": data,
+                "data": {
+                    "triggerAlarm": trigger_alarm.to_string(),
+                    "type": _type,
+                    "severity": _type.severity(),
+                    "title": _type.title(),
+                    "body": body,
+                },
                 "webpush": {
                     "headers": {
-                        "Urgency": "high"
+                        "Urgency": priority.webpush_urgency()
                     }
                 },
                 "android":{
-                    "priority": "high"
+                    "priority": priority.android_priority()
                 },
                 "apns": {
                     "headers": {
-                        "apns-priority": "10"
+                        "apns-priority": priority.apns_priority()
                     },
                     "payload": {
                         "aps": {
@@ -142,9 +242,25 @@ impl GoogleServices {
                 },
             }
         });
+        for platform in &self.display_platforms {
+            let notification = json!({
+                "title": _type.title(),
+                "body": body,
+            });
+            let message = &mut message["message"];
+            match platform {
+                FcmPlatform::Android => message["android"]["notification"] = notification,
+                FcmPlatform::Apns => message["apns"]["payload"]["aps"]["alert"] = notification,
+                FcmPlatform::Webpush => message["webpush"]["notification"] = notification,
+            }
+        }
 
-        let response = client
-            .post("https://fcm.googleapis.com/v1/projects/vpilot-alert/messages:send")
+        let response = self
+            .client
+            .post(format!(
+                "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+                self.project_id
+            ))
             .bearer_auth(self.token().await.access_token)
             .json(&message)
             .send()
@@ -161,7 +277,103 @@ impl GoogleServices {
     }
 }
 
-#[derive(Debug, Clone, Default, Deserialize)]
+#[async_trait]
+impl Notifier for GoogleServices {
+    async fn notify(
+        &self,
+        message: &str,
+        kind: &NotificationType,
+        trigger_alarm: bool,
+    ) -> Result<()> {
+        self.send_fcm_message(
+            message,
+            kind,
+            trigger_alarm,
+            Priority::of(kind, trigger_alarm),
+        )
+        .await
+    }
+}
+
+/// How urgently FCM should deliver a message and wake the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+impl Priority {
+    /// High for alarms, normal for low severity notifications.
+    pub fn of(_type: &NotificationType, trigger_alarm: bool) -> Priority {
+        if !trigger_alarm && _type.severity() == "low" {
+            Priority::Normal
+        } else {
+            Priority::High
+        }
+    }
+
+    fn webpush_urgency(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+        }
+    }
+
+    fn android_priority(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+        }
+    }
+
+    fn apns_priority(self) -> &'static str {
+        match self {
+            Priority::High => "10",
+            Priority::Normal => "5",
+        }
+    }
+}
+
+/// Refresh a minute before expiry, but never spin on tokens that expire immediately.
+fn refresh_interval(expires_in: u64) -> Duration {
+    Duration::from_secs(expires_in)
+        .saturating_sub(Duration::from_secs(60))
+        .max(Duration::from_secs(30))
+}
+
+/// Registration tokens are opaque, but always a long run of url-safe base64 and `:`.
+pub fn is_valid_fcm_token(token: &str) -> bool {
+    (32..=4096).contains(&token.len())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':'))
+}
+
+/// A token lets anyone push to the device, so logs only get its start and length.
+pub fn redact_token(token: &str) -> String {
+    let prefix = token.chars().take(6).collect::<String>();
+    format!("{prefix}... ({} chars)", token.chars().count())
+}
+
+async fn load_cached_token(path: &Path) -> Option<(Instant, TokenResponse)> {
+    let cached: CachedToken = serde_json::from_str(&read_to_string(path).await.ok()?).ok()?;
+    let age = Duration::from_secs(unix_now().saturating_sub(cached.fetched_at));
+    if age + Duration::from_secs(60) >= Duration::from_secs(cached.token.expires_in) {
+        return None;
+    }
+    Some((Instant::now().checked_sub(age)?, cached.token))
+}
+
+async fn store_cached_token(path: &Path, fetched_at: u64, token: &TokenResponse) -> Result<()> {
+    let cached = CachedToken {
+        fetched_at,
+        token: token.clone(),
+    };
+    write(path, serde_json::to_string(&cached)?).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct TokenResponse {
     access_token: String,
     expires_in: u64,
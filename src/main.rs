@@ -1,22 +1,41 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, Request, State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
+use chrono::DateTime;
 use clap::Parser;
-use serde::Deserialize;
-use std::sync::Arc;
+use geo::{Distance, Haversine, Point};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
 use tokio::{
-    fs::{read_to_string, write},
+    fs::read_to_string,
     spawn,
-    sync::Mutex,
+    sync::{broadcast, Mutex, Notify},
 };
-use tower_http::trace::TraceLayer;
-use tracing::{debug, error};
+use tower_http::{
+    cors::{AllowOrigin, Any, CorsLayer},
+    trace::TraceLayer,
+};
+use tracing::{debug, error, warn};
 use tracing_subscriber::{
     fmt::{
-        format::{Compact, DefaultFields},
+        format::{Compact, DefaultFields, Format},
         time::ChronoLocal,
     },
     layer::SubscriberExt,
@@ -25,38 +44,250 @@ use tracing_subscriber::{
 };
 
 use crate::{
-    fcm::GoogleServices,
-    route::{Route, RouteStatistics},
-    state::{AppState, AppStateType, Notification, NotificationType},
+    events::{Event, EventLog},
+    fcm::{is_valid_fcm_token, redact_token, FcmPlatform, GoogleServices},
+    metrics::Metrics,
+    notify::{DiscordWebhook, HttpWebhook, Notifier},
+    route::{
+        EtaModel, FixLookup, FlightPlan, LegEstimate, NoFlightPlan, Pilot, PilotNotConnected,
+        Route, RouteStatistics, TrackPoint, VatsimSource, Waypoint,
+    },
+    state::{
+        AlarmPolicy, AppState, AppStateType, CrashThresholds, LiveUpdate, Notification,
+        NotificationType, WaypointAlert,
+    },
+    store::{StateKey, StateStore},
 };
 
+mod events;
 mod fcm;
+mod metrics;
+mod notify;
 mod route;
 mod state;
+mod store;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Your callsign
-    #[arg(short, long)]
-    callsign: String,
+    /// Your callsign, can be repeated to monitor several flights
+    #[arg(short, long, required = true)]
+    callsign: Vec<String>,
+
+    /// Your VATSIM CID, used to find the first callsign's flight when it was filed under a different callsign
+    #[arg(long)]
+    cid: Option<u64>,
+
+    /// Also alert on radio messages containing this, eg. a telephony callsign, can be repeated
+    #[arg(long)]
+    callsign_alias: Vec<String>,
+
+    /// Navigation database path, can be repeated to merge several databases
+    #[arg(short, long, required = true)]
+    nav_db_path: Vec<String>,
 
-    /// Navigation database path
-    #[arg(short, long)]
-    nav_db_path: String,
+    /// Path to the firebase service account json
+    #[arg(long, default_value = "google-services.json")]
+    google_services_path: PathBuf,
+
+    /// Path to store the app's FCM token in, other state files are kept in the same directory
+    #[arg(long, default_value = "token")]
+    token_path: PathBuf,
+
+    /// Keep the FCM token, notifications and loop dumps in this SQLite database instead of files
+    #[arg(long)]
+    state_db: Option<PathBuf>,
 
     /// Interface to run server on
     #[arg(short, long, default_value = "0.0.0.0:8080")]
     interface: String,
+
+    /// Listen on `tcp://host:port` or a unix socket `unix:/path/to.sock` instead of `--interface`
+    #[arg(long)]
+    listen: Option<Listen>,
+
+    /// Seconds a crash condition must persist before the alarm is raised
+    #[arg(long, default_value_t = 0)]
+    alarm_grace_period: u64,
+
+    /// Seconds between re-sending an alarm that has not played yet
+    #[arg(long, default_value_t = 10)]
+    alarm_repeat_interval: u64,
+
+    /// Seconds an alarm may go unacknowledged before it is raised again, or vPilot is disconnected
+    #[arg(long, default_value_t = 180)]
+    alarm_timeout: u64,
+
+    /// Disconnect vPilot when an alarm times out instead of raising it again
+    #[arg(long)]
+    auto_disconnect: bool,
+
+    /// Re-send an unacknowledged alarm more often the longer it runs
+    #[arg(long)]
+    alarm_escalate: bool,
+
+    /// Append a JSONL timeline of flight events to this file
+    #[arg(long)]
+    event_log: Option<String>,
+
+    /// Notify once deviation drops below this many nm after a route deviation alarm
+    #[arg(long)]
+    back_on_route_threshold: Option<f64>,
+
+    /// Notify once per flight when the top of descent (3nm per 1000ft of the filed cruise altitude) is this many nm away
+    #[arg(long)]
+    top_of_descent_notice: Option<f64>,
+
+    /// How to estimate the time of arrival
+    #[arg(long, value_enum, default_value_t = EtaModel::Blended)]
+    eta_model: EtaModel,
+
+    /// Alert when altitude drops below this many feet
+    #[arg(long, default_value_t = 29000)]
+    min_altitude: i64,
+
+    /// Alert when descending faster than this many feet per minute
+    #[arg(long, default_value_t = 6000.0)]
+    max_descent_rate: f64,
+
+    /// Alert when ground speed drops below this many knots
+    #[arg(long, default_value_t = 300)]
+    min_groundspeed: i64,
+
+    /// Skip the altitude check within this many nm of the departure and arrival
+    #[arg(long, default_value_t = 100.0)]
+    altitude_airport_radius_nm: f64,
+
+    /// Alert when deviating more than this many nm from the route
+    #[arg(long, default_value_t = 30.0)]
+    max_deviation: f64,
+
+    /// Alert when route progress drops by more than this many percent between VATSIM updates
+    #[arg(long, default_value_t = 2.0)]
+    max_progress_reversal: f64,
+
+    /// Notify when the route deviation grows on this many consecutive VATSIM updates, before it reaches --max-deviation
+    #[arg(long, default_value_t = 4)]
+    deviation_trend_count: usize,
+
+    /// Alert when the position has not changed for more than this many VATSIM updates
+    #[arg(long, default_value_t = 10)]
+    stuck_count: usize,
+
+    /// Ignore track segments shorter than this many nm when detecting loops
+    #[arg(long, default_value_t = 0.5)]
+    loop_min_segment_nm: f64,
+
+    /// Only count loops between track segments at least this many positions apart
+    #[arg(long, default_value_t = 3)]
+    loop_min_index_gap: usize,
+
+    /// Ignore track positions within this many nm of the departure and arrival when detecting loops
+    #[arg(long, default_value_t = 20.0)]
+    loop_airport_radius_nm: f64,
+
+    /// Keep at most this many notifications, dropping the oldest
+    #[arg(long, default_value_t = 200)]
+    notifications_capacity: usize,
+
+    /// POST notifications to this URL instead of sending them through FCM
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// Also post notifications to this Discord webhook
+    #[arg(long)]
+    discord_webhook: Option<String>,
+
+    /// Do not send notifications to the android app through FCM
+    #[arg(long)]
+    no_fcm: bool,
+
+    /// Also send a title and body for the OS to display on this platform, can be repeated. Messages are data-only by default
+    #[arg(long, value_enum)]
+    fcm_display: Vec<FcmPlatform>,
+
+    /// Seconds during which repeated radio calls from the same sender do not re-trigger the alarm
+    #[arg(long, default_value_t = 20)]
+    radio_debounce: u64,
+
+    /// Allow browser requests from this origin, can be repeated, `*` allows any origin
+    #[arg(long)]
+    cors_origin: Vec<String>,
+
+    /// URL of the vatsim v3 data feed, eg. a mirror or a local mock server
+    #[arg(long, default_value = route::DEFAULT_VATSIM_URL)]
+    vatsim_url: String,
+
+    /// Send this as a bearer token when fetching the vatsim data feed
+    #[arg(long)]
+    vatsim_token: Option<String>,
+
+    /// Seconds between fetches of the vatsim data feed
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    vatsim_poll_secs: u64,
+
+    /// Seconds between state loop runs, which re-send alarms and check for crashes
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    state_loop_secs: u64,
+
+    /// Do not fetch NAT tracks from nattrak, follow the coordinates filed in the route instead
+    #[arg(long)]
+    no_nat_lookup: bool,
+
+    /// Read vatsim snapshots from this file (one per line) or directory instead of the live feed
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Append every new aircraft position to this JSONL file
+    #[arg(long)]
+    track_log: Option<String>,
+
+    /// Also write logs to this directory, in a new file every day
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Require this key as an `Authorization: Bearer` header on API requests
+    #[arg(long)]
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            Ok(Listen::Tcp(addr.to_owned()))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            if cfg!(not(unix)) {
+                return Err("unix sockets are not supported on this platform".to_owned());
+            }
+            Ok(Listen::Unix(PathBuf::from(path)))
+        } else {
+            Err(format!(
+                "expected tcp://host:port or unix:/path/to.sock, got {s}"
+            ))
+        }
+    }
 }
 
-fn get_layer<S>(
-    layer: tracing_subscriber::fmt::Layer<S>,
-) -> tracing_subscriber::fmt::Layer<
-    S,
-    DefaultFields,
-    tracing_subscriber::fmt::format::Format<Compact, ChronoLocal>,
-> {
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn get_layer<S, W>(
+    layer: tracing_subscriber::fmt::Layer<S, DefaultFields, Format, W>,
+) -> tracing_subscriber::fmt::Layer<S, DefaultFields, Format<Compact, ChronoLocal>, W> {
     layer
         .with_timer(ChronoLocal::new("%v %k:%M:%S %z".to_owned()))
         .compact()
@@ -64,55 +295,212 @@ fn get_layer<S>(
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    let listen = args
+        .listen
+        .clone()
+        .unwrap_or_else(|| Listen::Tcp(args.interface.clone()));
+
     let log_level = std::env::var("LOG").unwrap_or("warn".to_owned());
+    let file_layer = args.log_file.as_ref().map(|dir| {
+        get_layer(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(tracing_appender::rolling::daily(dir, "vpilot-alert.log")),
+        )
+    });
     tracing_subscriber::registry()
         .with(
             EnvFilter::new(format!("vpilot_alert={log_level}"))
                 .add_directive(format!("tower_http::trace={log_level}").parse().unwrap()),
         )
         .with(get_layer(tracing_subscriber::fmt::layer()))
+        .with(file_layer)
         .init();
+    for path in &args.nav_db_path {
+        if let Err(err) = route::check_nav_db(path) {
+            error!("Invalid nav db: {err:#}");
+            std::process::exit(1);
+        }
+    }
 
-    let args = Args::parse();
-
-    let token_path = std::path::Path::new("token");
-    let token = if token_path.exists() {
-        read_to_string(token_path)
+    let state_dir = args.token_path.parent().unwrap_or(std::path::Path::new(""));
+    let store = match &args.state_db {
+        Some(path) => StateStore::sqlite(path).expect("Failed to open state db"),
+        None => StateStore::files(&args.token_path),
+    };
+    // Environment variables take precedence, eg. for secrets injected into a container
+    let token = match std::env::var("FCM_TOKEN") {
+        Ok(token) => token,
+        Err(_) => store
+            .load(StateKey::Token)
             .await
-            .expect("Failed to read token file")
-    } else {
-        String::new()
+            .expect("Failed to read token")
+            .unwrap_or_default(),
     };
 
-    let google_services: GoogleServices = serde_json::from_str(
-        &read_to_string("google-services.json")
+    let client = reqwest::Client::new();
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    let mut google_services = None;
+    if let Some(url) = args.webhook_url {
+        notifiers.push(Box::new(HttpWebhook::new(url, client.clone())));
+    } else if !args.no_fcm {
+        let google_services_json = match std::env::var("GOOGLE_SERVICES_JSON") {
+            Ok(json) => json,
+            Err(_) => read_to_string(&args.google_services_path)
+                .await
+                .expect("Failed to read google-services.json"),
+        };
+        let services: GoogleServices =
+            serde_json::from_str::<GoogleServices>(&google_services_json)
+                .expect("Failed to parse google-services.json")
+                .with_oauth_cache_path(state_dir.join("oauth_token.json"))
+                .with_client(client.clone())
+                .with_display_platforms(args.fcm_display.clone());
+        services
+            .login()
             .await
-            .expect("Failed to read google-services.json"),
-    )
-    .expect("Failed to parse google-services.json");
-    google_services
-        .login()
-        .await
-        .expect("Failed to login to google services");
+            .expect("Failed to login to google services");
+        services.set_recipient_token(token).await;
+        notifiers.push(Box::new(services.clone()));
+        google_services = Some(services);
+    }
+    if let Some(url) = args.discord_webhook {
+        notifiers.push(Box::new(DiscordWebhook::new(url, client.clone())));
+    }
 
-    let mut route = Route::new(&args.nav_db_path, &args.callsign).expect("Failed to create route");
-    let stats = route
-        .route_statistics()
+    let events = EventLog::open(args.event_log.as_deref()).expect("Failed to open event log");
+    let thresholds = CrashThresholds {
+        min_altitude: args.min_altitude,
+        max_descent_rate: args.max_descent_rate,
+        min_ground_speed: args.min_groundspeed,
+        max_deviation: args.max_deviation,
+        max_progress_reversal: args.max_progress_reversal,
+        stuck_count: args.stuck_count,
+        deviation_trend_count: args.deviation_trend_count,
+        loop_min_segment_nm: args.loop_min_segment_nm,
+        loop_min_index_gap: args.loop_min_index_gap,
+        loop_airport_radius_nm: args.loop_airport_radius_nm,
+        altitude_airport_radius_nm: args.altitude_airport_radius_nm,
+    };
+    route::set_vatsim_source(VatsimSource {
+        url: args.vatsim_url.clone(),
+        bearer_token: args.vatsim_token.clone(),
+    })
+    .expect("Failed to set vatsim source");
+    route::set_vatsim_poll_interval(Duration::from_secs(args.vatsim_poll_secs))
+        .expect("Failed to set vatsim poll interval");
+    if args.no_nat_lookup {
+        route::disable_nat_lookup();
+    }
+    if let Some(path) = &args.replay {
+        route::start_replay(path).expect("Failed to load vatsim replay");
+    }
+
+    let mut notifications = match store
+        .load(StateKey::Notifications)
         .await
-        .expect("Failed to get route statistics");
+        .expect("Failed to read notifications")
+    {
+        Some(saved) => serde_json::from_str::<VecDeque<Notification>>(&saved)
+            .expect("Failed to parse saved notifications"),
+        None => VecDeque::new(),
+    };
+    while notifications.len() > args.notifications_capacity {
+        notifications.pop_front();
+    }
+    // Saved before notifications had ids
+    for notification in notifications.iter_mut().filter(|n| n.id.is_empty()) {
+        notification.id = Notification::next_id(notification.epoch_ms);
+    }
 
+    let track_log = EventLog::open(args.track_log.as_deref()).expect("Failed to open track log");
+    let mut routes = HashMap::new();
+    let mut stats = HashMap::new();
+    for (i, callsign) in args.callsign.iter().enumerate() {
+        let mut route = Route::new(
+            &args.nav_db_path,
+            callsign,
+            events.clone(),
+            thresholds.clone(),
+            store.clone(),
+            args.eta_model,
+            client.clone(),
+        )
+        .expect("Failed to create route")
+        .with_track_log(track_log.clone())
+        .with_cid(args.cid.filter(|_| i == 0));
+        // Offline or not yet filed flights are picked up by the VATSIM poll later on
+        match route.route_statistics().await {
+            Ok(route_stats) => {
+                stats.insert(callsign.clone(), route_stats);
+            }
+            Err(err) if err.is::<PilotNotConnected>() => {
+                warn!("{callsign} is not connected to VATSIM yet");
+            }
+            // Already logged by the route
+            Err(err) if err.is::<NoFlightPlan>() => {}
+            Err(err) => error!("Failed to get route statistics for {callsign}: {}", err),
+        }
+        routes.insert(callsign.clone(), Arc::new(Mutex::new(route)));
+    }
+
+    let was_on_network = stats.keys().cloned().collect();
+    let ready = stats.contains_key(&args.callsign[0]);
     let app_state = Arc::new(Mutex::new(AppState {
-        recipient_token: token,
         google_services,
-        notifications: Vec::new(),
-        callsign: args.callsign,
+        notifiers,
+        notifications,
+        notifications_capacity: args.notifications_capacity,
+        store,
+        updates: broadcast::channel(64).0,
+        ready,
+        callsigns: args.callsign.clone(),
+        callsign_aliases: args.callsign_alias,
         vpilot_connected: true,
         alarm: None,
         stats,
-        route,
+        routes,
+        poll_now: Arc::new(Notify::new()),
         alert_crashes: false,
+        alarm_grace_period: Duration::from_secs(args.alarm_grace_period),
+        pending_alarms: HashMap::new(),
+        tripped_checks: HashMap::new(),
+        crash_alerted: HashMap::new(),
+        events,
+        metrics: Metrics::default(),
+        back_on_route_threshold: args.back_on_route_threshold,
+        top_of_descent_notice: args.top_of_descent_notice,
+        tod_notified: HashMap::new(),
+        waypoint_alerts: Vec::new(),
+        squawk_alerted: HashMap::new(),
+        deviation_alerted: HashSet::new(),
+        thresholds,
+        alarm_policy: AlarmPolicy {
+            repeat_interval: Duration::from_secs(args.alarm_repeat_interval),
+            max_duration: Duration::from_secs(args.alarm_timeout),
+            escalate: args.alarm_escalate,
+            auto_disconnect: args.auto_disconnect,
+        },
+        radio_senders: HashMap::new(),
+        radio_debounce: Duration::from_secs(args.radio_debounce),
+        snooze_until: None,
+        interface: listen.to_string(),
+        alert_types: NotificationType::ALL
+            .into_iter()
+            .map(|_type| (_type, true))
+            .collect(),
+        was_on_network,
+        network_missed_since: HashMap::new(),
+        altitude_samples: HashMap::new(),
+        vertical_rates: HashMap::new(),
+        progress_samples: HashMap::new(),
+        progress_reversed: HashSet::new(),
+        deviation_samples: HashMap::new(),
+        deviation_trend_alerted: HashSet::new(),
+        state_loop_interval: Duration::from_secs(args.state_loop_secs),
     }));
-    let api_router = Router::new()
+    let mut api_router = Router::new()
         .route("/fcm-token", post(save_token))
         .route("/private-message", post(private_message))
         .route("/radio-message", post(radio_message))
@@ -125,23 +513,135 @@ async fn main() {
             "/notifications",
             get(get_notifications).delete(clear_notifications),
         )
+        .route("/notifications/{id}", delete(delete_notification))
         .route("/alert_crashes/{alert_crashes}", post(set_alert_crashes))
         .route("/alert_crashes", get(get_alert_crashes))
+        .route("/alert_types", get(get_alert_types).post(set_alert_types))
+        .route("/config", get(get_config))
+        .route("/callsigns", get(get_callsigns))
+        .route("/ws", get(live_updates))
         .route("/stats", get(get_stats))
+        .route("/stats/{callsign}", get(get_callsign_stats))
+        .route("/pilot", get(get_pilot))
+        .route("/pilot/{callsign}", get(get_callsign_pilot))
+        .route(
+            "/route",
+            get(get_route)
+                .post(set_route_override)
+                .delete(clear_route_override),
+        )
+        .route(
+            "/route/{callsign}",
+            get(get_callsign_route)
+                .post(set_callsign_route_override)
+                .delete(clear_callsign_route_override),
+        )
+        .route("/track", get(get_track))
+        .route("/track/{callsign}", get(get_callsign_track))
+        .route(
+            "/waypoint-alert",
+            get(get_waypoint_alerts).post(add_waypoint_alert),
+        )
+        .route("/reload-navdb", post(reload_nav_db))
+        .route("/eta", get(get_eta))
+        .route("/eta/{callsign}", get(get_callsign_eta))
+        .route("/geojson", get(get_geojson))
+        .route("/geojson/{callsign}", get(get_callsign_geojson))
         .route("/alarm", delete(stop_alarm).post(received_alarm))
+        .route("/ack", post(acknowledge))
+        .route("/snooze/{seconds}", post(snooze_alarm))
         .route("/notify", post(send_notification))
-        .with_state(app_state.clone());
+        .route("/test-alarm", post(send_test_alarm))
+        .route("/navdb/fix/{ident}", get(get_navdb_fix))
+        .with_state(app_state.clone())
+        .layer(middleware::from_fn_with_state(
+            args.api_key.map(Arc::<str>::from),
+            require_api_key,
+        ));
+    if let Some(cors) = cors_layer(&args.cors_origin) {
+        api_router = api_router.layer(cors);
+    }
 
     let app = Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_health))
+        .with_state(app_state.clone())
         .nest("/vpilot-alert/api/", api_router)
         .layer(TraceLayer::new_for_http())
         .fallback(handler_404);
 
-    spawn(AppState::state_loop(app_state));
+    spawn(AppState::state_loop(app_state.clone()));
+    spawn(AppState::vatsim_loop(app_state.clone()));
 
-    debug!("Starting server on 8080");
-    let listener = tokio::net::TcpListener::bind(args.interface).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    debug!("Starting server on {listen}");
+    match listen {
+        Listen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            // A socket left behind by a previous run would fail the bind, anything else is kept
+            if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                if !metadata.file_type().is_socket() {
+                    error!(
+                        "{} already exists and is not a socket, refusing to replace it",
+                        path.display()
+                    );
+                    std::process::exit(1);
+                }
+                std::fs::remove_file(&path).expect("Failed to remove stale socket");
+            }
+            let listener = match tokio::net::UnixListener::bind(&path) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to listen on {}: {err}", path.display());
+                    std::process::exit(1);
+                }
+            };
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+            let _ = std::fs::remove_file(&path);
+        }
+        #[cfg(not(unix))]
+        Listen::Unix(_) => unreachable!("Unix sockets are rejected when parsing --listen"),
+    }
+
+    let state = app_state.lock().await;
+    if let Err(err) = state.save().await {
+        error!("Failed to save state on shutdown: {}", err);
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    debug!("Shutting down");
 }
 
 #[derive(Deserialize)]
@@ -149,18 +649,103 @@ struct TokenPayload {
     token: String,
 }
 
+fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| origin.parse().expect("Invalid CORS origin")),
+        )
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(Any)
+            // A `*` wildcard never covers `Authorization`, which the API key is sent in
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE]),
+    )
+}
+
+#[derive(Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+async fn require_api_key(
+    State(api_key): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if let Some(api_key) = api_key {
+        // Browser WebSockets cannot set headers, so the key may also be passed in the query
+        let query_key = Query::<ApiKeyQuery>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(query)| query.api_key);
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or(query_key.as_deref())
+            // Constant time, so response timing does not give the key away byte by byte
+            .is_some_and(|key| bool::from(key.as_bytes().ct_eq(api_key.as_bytes())));
+        if !authorized {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+async fn get_metrics(state: State<AppStateType>) -> String {
+    let state = state.lock().await;
+    state.metrics.render(&state.stats)
+}
+
+async fn get_health(state: State<AppStateType>) -> StatusCode {
+    if state.lock().await.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
 async fn handler_404() -> impl axum::response::IntoResponse {
     (StatusCode::NOT_FOUND, "nothing to see here")
 }
 
-async fn save_token(state: State<AppStateType>, Json(payload): Json<TokenPayload>) -> StatusCode {
-    let mut state = state.lock().await;
-    state.recipient_token = payload.token;
-    write("token", &*state.recipient_token)
-        .await
-        .expect("Failed to write token file");
-    debug!("Token saved: {}", state.recipient_token);
-    StatusCode::OK
+async fn save_token(
+    state: State<AppStateType>,
+    Json(payload): Json<TokenPayload>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !is_valid_fcm_token(&payload.token) {
+        error!(
+            "Rejected malformed FCM token: {}",
+            redact_token(&payload.token)
+        );
+        return Err((StatusCode::BAD_REQUEST, "Malformed FCM token".to_owned()));
+    }
+
+    let state = state.lock().await;
+    if let Err(err) = state.store.save(StateKey::Token, &payload.token).await {
+        error!("Failed to save token: {}", err);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save token: {err}"),
+        ));
+    }
+    if let Some(google_services) = &state.google_services {
+        google_services
+            .set_recipient_token(payload.token.clone())
+            .await;
+    }
+    debug!("Token saved: {}", redact_token(&payload.token));
+    Ok(StatusCode::OK)
 }
 
 #[derive(Deserialize)]
@@ -198,22 +783,39 @@ async fn radio_message(
     Json(payload): Json<RadioMessage>,
 ) -> StatusCode {
     let mut state = state.lock().await;
-    if payload
-        .message
-        .to_lowercase()
-        .contains(state.callsign.to_lowercase().as_str())
+    let message = payload.message.to_lowercase();
+    if state
+        .callsigns
+        .iter()
+        .chain(&state.callsign_aliases)
+        .any(|callsign| message.contains(callsign.to_lowercase().as_str()))
     {
-        if let Err(err) = state
-            .send_notification(
-                format!(
-                    "{} @ {:?}: {}",
-                    payload.from, payload.frequencies, payload.message
-                ),
+        let message = format!(
+            "{} @ {:?}: {}",
+            payload.from, payload.frequencies, payload.message
+        );
+        let debounced = state
+            .radio_senders
+            .get(&payload.from)
+            .is_some_and(|last| last.elapsed() < state.radio_debounce);
+        if debounced
+            && state.update_notification(
+                &format!("{} @", payload.from),
                 NotificationType::RadioMessage,
+                message.clone(),
             )
+        {
+            return StatusCode::OK;
+        }
+
+        match state
+            .send_notification(message, NotificationType::RadioMessage)
             .await
         {
-            error!("Failed to send notification: {}", err);
+            Ok(()) => {
+                state.radio_senders.insert(payload.from, Instant::now());
+            }
+            Err(err) => error!("Failed to send notification: {}", err),
         }
     }
     StatusCode::OK
@@ -239,7 +841,13 @@ async fn selcal_alert(state: State<AppStateType>, Json(payload): Json<SelcalAler
 }
 
 async fn set_disconnect_vpilot(state: State<AppStateType>) -> StatusCode {
-    state.lock().await.vpilot_connected = false;
+    let mut state = state.lock().await;
+    if state.vpilot_connected {
+        state
+            .events
+            .record(Event::ConnectionChanged { connected: false });
+    }
+    state.vpilot_connected = false;
     StatusCode::OK
 }
 
@@ -247,9 +855,28 @@ async fn get_connection_status(state: State<AppStateType>) -> Json<bool> {
     Json(state.lock().await.vpilot_connected)
 }
 
-async fn get_notifications(state: State<AppStateType>) -> Json<Vec<Notification>> {
+#[derive(Deserialize)]
+struct NotificationsQuery {
+    /// Only notifications newer than this epoch in milliseconds
+    since: Option<i64>,
+    /// At most this many notifications, oldest first
+    limit: Option<usize>,
+}
+
+async fn get_notifications(
+    Query(query): Query<NotificationsQuery>,
+    state: State<AppStateType>,
+) -> Json<Vec<Notification>> {
     let state = state.lock().await;
-    Json(state.notifications.clone())
+    Json(
+        state
+            .notifications
+            .iter()
+            .filter(|n| query.since.is_none_or(|since| n.epoch_ms > since))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect(),
+    )
 }
 async fn clear_notifications(state: State<AppStateType>) -> StatusCode {
     let mut state = state.lock().await;
@@ -257,12 +884,30 @@ async fn clear_notifications(state: State<AppStateType>) -> StatusCode {
     StatusCode::OK
 }
 
-async fn stop_alarm(state: State<AppStateType>) -> StatusCode {
+async fn delete_notification(Path(id): Path<String>, state: State<AppStateType>) -> StatusCode {
     let mut state = state.lock().await;
-    if state.alarm.is_some() {
-        state.alarm = None;
+    match state.notifications.iter().position(|n| n.id == id) {
+        Some(index) => {
+            state.notifications.remove(index);
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
     }
+}
+
+async fn stop_alarm(state: State<AppStateType>) -> StatusCode {
+    let mut state = state.lock().await;
+    state.stop_alarm();
+    StatusCode::OK
+}
 
+/// Stops the alarm and marks every notification read in one go.
+async fn acknowledge(state: State<AppStateType>) -> StatusCode {
+    let mut state = state.lock().await;
+    state.stop_alarm();
+    for notification in state.notifications.iter_mut() {
+        notification.read = true;
+    }
     StatusCode::OK
 }
 
@@ -274,6 +919,12 @@ async fn received_alarm(state: State<AppStateType>) -> StatusCode {
     StatusCode::OK
 }
 
+async fn snooze_alarm(Path(seconds): Path<u64>, state: State<AppStateType>) -> StatusCode {
+    let mut state = state.lock().await;
+    state.snooze_until = Some(Instant::now() + Duration::from_secs(seconds));
+    StatusCode::OK
+}
+
 #[derive(Deserialize)]
 struct SetAlertCrashes {
     alert_crashes: bool,
@@ -293,9 +944,425 @@ async fn get_alert_crashes(state: State<AppStateType>) -> Json<bool> {
     Json(state.alert_crashes)
 }
 
-async fn get_stats(state: State<AppStateType>) -> Json<RouteStatistics> {
+async fn set_alert_types(
+    state: State<AppStateType>,
+    Json(payload): Json<HashMap<NotificationType, bool>>,
+) -> StatusCode {
+    let mut state = state.lock().await;
+    state.alert_types.extend(payload);
+    StatusCode::OK
+}
+
+async fn get_alert_types(state: State<AppStateType>) -> Json<HashMap<NotificationType, bool>> {
     let state = state.lock().await;
-    Json(state.stats.clone())
+    Json(state.alert_types.clone())
+}
+
+async fn get_callsigns(state: State<AppStateType>) -> Json<Vec<String>> {
+    let state = state.lock().await;
+    Json(state.callsigns.clone())
+}
+
+#[derive(Serialize)]
+struct Config {
+    callsign: String,
+    callsigns: Vec<String>,
+    alert_crashes: bool,
+    vpilot_connected: bool,
+    has_flight_plan: bool,
+    nav_db_versions: Vec<usize>,
+    navdb_cycle: String,
+    interface: String,
+    thresholds: CrashThresholds,
+    alert_types: HashMap<NotificationType, bool>,
+}
+
+async fn get_config(state: State<AppStateType>) -> Json<Config> {
+    let (mut config, route) = {
+        let state = state.lock().await;
+        let config = Config {
+            callsign: state.primary_callsign().to_owned(),
+            callsigns: state.callsigns.clone(),
+            alert_crashes: state.alert_crashes,
+            vpilot_connected: state.vpilot_connected,
+            has_flight_plan: true,
+            nav_db_versions: Vec::new(),
+            navdb_cycle: String::new(),
+            interface: state.interface.clone(),
+            thresholds: state.thresholds.clone(),
+            alert_types: state.alert_types.clone(),
+        };
+        (config, state.primary_route())
+    };
+    // Without holding the state, which a VATSIM poll in progress would otherwise block
+    let route = route.lock().await;
+    config.has_flight_plan = route.has_flight_plan();
+    config.nav_db_versions = route.db_versions().to_vec();
+    config.navdb_cycle = route.navdb_cycle().to_owned();
+    Json(config)
+}
+
+async fn live_updates(ws: WebSocketUpgrade, state: State<AppStateType>) -> impl IntoResponse {
+    let updates = state.lock().await.updates.subscribe();
+    ws.on_upgrade(|socket| push_live_updates(socket, updates))
+}
+
+async fn push_live_updates(mut socket: WebSocket, mut updates: broadcast::Receiver<LiveUpdate>) {
+    loop {
+        tokio::select! {
+            update = updates.recv() => {
+                let update = match update {
+                    Ok(update) => update,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let text = serde_json::to_string(&update).expect("Serialize live update");
+                if socket.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // Clients only listen, anything but a regular message ends the connection
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Units {
+    #[default]
+    Nautical,
+    Metric,
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// `metric` reports distances in kilometers, the field names keep their `nm` suffix
+    #[serde(default)]
+    units: Units,
+    /// Format the ETA in UTC instead of the server's local time
+    #[serde(default)]
+    utc: bool,
+}
+
+/// Converts the stats for the response only, they are computed and kept in nm.
+fn convert_stats(mut stats: RouteStatistics, query: &StatsQuery) -> RouteStatistics {
+    if query.units == Units::Metric {
+        const KM_PER_NM: f64 = 1.852;
+        stats.route_deviation *= KM_PER_NM;
+        stats.deviation_rate *= KM_PER_NM;
+        stats.dist_next_wp *= KM_PER_NM;
+        stats.dist_remaining_nm *= KM_PER_NM;
+        stats.dist_to_tod_nm *= KM_PER_NM;
+        stats.dist_to_airport_nm *= KM_PER_NM;
+    }
+    if query.utc {
+        if let Some(eta) =
+            DateTime::from_timestamp_millis(stats.eta_epoch_ms).filter(|_| stats.eta_epoch_ms != 0)
+        {
+            stats.eta = eta.format("%Y-%m-%d %H:%M UTC").to_string();
+        }
+    }
+    stats
+}
+
+async fn get_stats(
+    Query(query): Query<StatsQuery>,
+    state: State<AppStateType>,
+) -> Result<Json<RouteStatistics>, StatusCode> {
+    let state = state.lock().await;
+    state
+        .stats
+        .get(state.primary_callsign())
+        .cloned()
+        .map(|stats| Json(convert_stats(stats, &query)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_callsign_stats(
+    Path(callsign): Path<String>,
+    Query(query): Query<StatsQuery>,
+    state: State<AppStateType>,
+) -> Result<Json<RouteStatistics>, StatusCode> {
+    let state = state.lock().await;
+    state
+        .stats
+        .get(&callsign)
+        .cloned()
+        .map(|stats| Json(convert_stats(stats, &query)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_pilot(state: State<AppStateType>) -> Result<Json<Pilot>, StatusCode> {
+    let state = state.lock().await;
+    state
+        .stats
+        .get(state.primary_callsign())
+        .map(|stats| Json(stats.pilot.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_callsign_pilot(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<Pilot>, StatusCode> {
+    let state = state.lock().await;
+    state
+        .stats
+        .get(&callsign)
+        .map(|stats| Json(stats.pilot.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_route(state: State<AppStateType>) -> Json<Vec<Waypoint>> {
+    let route = state.lock().await.primary_route();
+    let route = route.lock().await;
+    Json(route.current_waypoints())
+}
+
+async fn get_callsign_route(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<Vec<Waypoint>>, StatusCode> {
+    let route = state
+        .lock()
+        .await
+        .route(&callsign)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let route = route.lock().await;
+    Ok(Json(route.current_waypoints()))
+}
+
+#[derive(Deserialize)]
+struct RouteOverride {
+    route: String,
+    departure: String,
+    arrival: String,
+}
+
+async fn set_route_override(
+    state: State<AppStateType>,
+    Json(payload): Json<RouteOverride>,
+) -> Result<Json<Vec<Waypoint>>, (StatusCode, String)> {
+    let callsign = state.lock().await.primary_callsign().to_owned();
+    set_callsign_route_override(Path(callsign), state, Json(payload)).await
+}
+
+async fn set_callsign_route_override(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+    Json(payload): Json<RouteOverride>,
+) -> Result<Json<Vec<Waypoint>>, (StatusCode, String)> {
+    let (route, poll_now) = {
+        let state = state.lock().await;
+        let route = state.route(&callsign).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Unknown callsign {callsign}"),
+        ))?;
+        (route, state.poll_now.clone())
+    };
+    let flight_plan = FlightPlan {
+        route: payload.route,
+        departure: payload.departure.to_uppercase(),
+        arrival: payload.arrival.to_uppercase(),
+        ..Default::default()
+    };
+    let waypoints = route
+        .lock()
+        .await
+        .set_route_override(flight_plan)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    // Recompute the statistics against the new route straight away
+    poll_now.notify_one();
+    Ok(Json(waypoints))
+}
+
+async fn clear_route_override(state: State<AppStateType>) -> StatusCode {
+    let callsign = state.lock().await.primary_callsign().to_owned();
+    clear_callsign_route_override(Path(callsign), state).await
+}
+
+async fn clear_callsign_route_override(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> StatusCode {
+    let (route, poll_now) = {
+        let state = state.lock().await;
+        let Some(route) = state.route(&callsign) else {
+            return StatusCode::NOT_FOUND;
+        };
+        (route, state.poll_now.clone())
+    };
+    route.lock().await.clear_route_override();
+    poll_now.notify_one();
+    StatusCode::OK
+}
+
+async fn get_track(state: State<AppStateType>) -> Json<Vec<TrackPoint>> {
+    let route = state.lock().await.primary_route();
+    let route = route.lock().await;
+    Json(route.track().to_vec())
+}
+
+async fn get_callsign_track(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<Vec<TrackPoint>>, StatusCode> {
+    let route = state
+        .lock()
+        .await
+        .route(&callsign)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let route = route.lock().await;
+    Ok(Json(route.track().to_vec()))
+}
+
+#[derive(Deserialize)]
+struct WaypointAlertRequest {
+    fix: String,
+    distance_nm: f64,
+    /// Defaults to the first callsign
+    callsign: Option<String>,
+}
+
+async fn get_waypoint_alerts(state: State<AppStateType>) -> Json<Vec<WaypointAlert>> {
+    Json(state.lock().await.waypoint_alerts.clone())
+}
+
+async fn add_waypoint_alert(
+    state: State<AppStateType>,
+    Json(payload): Json<WaypointAlertRequest>,
+) -> Result<Json<WaypointAlert>, (StatusCode, String)> {
+    let (callsign, route) = {
+        let state = state.lock().await;
+        let callsign = payload
+            .callsign
+            .unwrap_or_else(|| state.primary_callsign().to_owned());
+        let route = state.route(&callsign).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Unknown callsign {callsign}"),
+        ))?;
+        (callsign, route)
+    };
+    let route = route.lock().await;
+    let fix = payload.fix.to_uppercase();
+
+    // Prefer the fix on the route, otherwise the one of that name closest to the aircraft
+    let mut waypoints = route.current_waypoints().into_iter();
+    let position = waypoints.next();
+    let waypoint = match waypoints.find(|wpt| wpt.id == fix) {
+        Some(waypoint) => waypoint,
+        None => {
+            let lookup = route
+                .lookup_fix(&fix)
+                .await
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            let distance = |wpt: &Waypoint| {
+                position.as_ref().map_or(0.0, |pos| {
+                    Haversine.distance(Point::new(pos.lon, pos.lat), Point::new(wpt.lon, wpt.lat))
+                })
+            };
+            lookup
+                .candidates
+                .into_iter()
+                .map(|cand| cand.waypoint)
+                .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                .ok_or((StatusCode::NOT_FOUND, format!("Unknown fix {fix}")))?
+        }
+    };
+
+    let alert = WaypointAlert {
+        callsign,
+        fix,
+        distance_nm: payload.distance_nm,
+        lat: waypoint.lat,
+        lon: waypoint.lon,
+    };
+    state.lock().await.waypoint_alerts.push(alert.clone());
+    Ok(Json(alert))
+}
+
+#[derive(Deserialize)]
+struct ReloadNavDb {
+    nav_db_path: Vec<String>,
+}
+
+/// Reopens the nav databases for every callsign, eg. after installing a new AIRAC cycle.
+async fn reload_nav_db(
+    state: State<AppStateType>,
+    payload: Option<Json<ReloadNavDb>>,
+) -> Result<Json<Vec<usize>>, (StatusCode, String)> {
+    let paths = payload.map(|Json(p)| p.nav_db_path).unwrap_or_default();
+    for path in &paths {
+        route::check_nav_db(path).map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    }
+
+    let (routes, primary, poll_now) = {
+        let state = state.lock().await;
+        (
+            state.routes.values().cloned().collect::<Vec<_>>(),
+            state.primary_route(),
+            state.poll_now.clone(),
+        )
+    };
+    // Only swap once every callsign has its new worker, so a failure leaves all of them as they were
+    let mut workers = Vec::with_capacity(routes.len());
+    for route in &routes {
+        let worker = route
+            .lock()
+            .await
+            .open_nav_db(&paths)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?;
+        workers.push(worker);
+    }
+    for (route, worker) in routes.iter().zip(workers) {
+        route.lock().await.swap_nav_db(worker);
+    }
+    poll_now.notify_one();
+    let versions = primary.lock().await.db_versions().to_vec();
+    Ok(Json(versions))
+}
+
+async fn get_eta(state: State<AppStateType>) -> Json<Vec<LegEstimate>> {
+    let route = state.lock().await.primary_route();
+    let route = route.lock().await;
+    Json(route.legs().to_vec())
+}
+
+async fn get_callsign_eta(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<Vec<LegEstimate>>, StatusCode> {
+    let route = state
+        .lock()
+        .await
+        .route(&callsign)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let route = route.lock().await;
+    Ok(Json(route.legs().to_vec()))
+}
+
+async fn get_geojson(state: State<AppStateType>) -> Json<serde_json::Value> {
+    let route = state.lock().await.primary_route();
+    let route = route.lock().await;
+    Json(route.to_geojson())
+}
+
+async fn get_callsign_geojson(
+    Path(callsign): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let route = state
+        .lock()
+        .await
+        .route(&callsign)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let route = route.lock().await;
+    Ok(Json(route.to_geojson()))
 }
 
 async fn send_notification(state: State<AppStateType>) -> StatusCode {
@@ -311,3 +1378,31 @@ async fn send_notification(state: State<AppStateType>) -> StatusCode {
     }
     StatusCode::OK
 }
+
+/// Long enough to see a few repeats and acknowledge them.
+const TEST_ALARM_TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn send_test_alarm(state: State<AppStateType>) -> StatusCode {
+    let mut state = state.lock().await;
+    if let Err(err) = state.send_test_alarm(TEST_ALARM_TIMEOUT).await {
+        error!("Failed to send test alarm: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    StatusCode::OK
+}
+
+async fn get_navdb_fix(
+    Path(ident): Path<String>,
+    state: State<AppStateType>,
+) -> Result<Json<FixLookup>, StatusCode> {
+    let route = state.lock().await.primary_route();
+    let route = route.lock().await;
+    match route.lookup_fix(&ident).await {
+        Ok(lookup) if lookup.candidates.is_empty() => Err(StatusCode::NOT_FOUND),
+        Ok(lookup) => Ok(Json(lookup)),
+        Err(err) => {
+            error!("Failed to look up fix {ident}: {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
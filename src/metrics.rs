@@ -0,0 +1,60 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{route::RouteStatistics, state::NotificationType};
+
+#[derive(Default)]
+pub struct Metrics {
+    pub notifications_sent: HashMap<NotificationType, u64>,
+    pub vatsim_fetch_failures: u64,
+}
+
+impl Metrics {
+    /// Prometheus text exposition of the counters and the per-callsign route gauges.
+    pub fn render(&self, stats: &HashMap<String, RouteStatistics>) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE vpilot_alert_notifications_sent_total counter").unwrap();
+        for _type in NotificationType::ALL {
+            let count = self.notifications_sent.get(&_type).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "vpilot_alert_notifications_sent_total{{type=\"{_type:?}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# TYPE vpilot_alert_vatsim_fetch_failures_total counter"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "vpilot_alert_vatsim_fetch_failures_total {}",
+            self.vatsim_fetch_failures
+        )
+        .unwrap();
+
+        let mut callsigns = stats.keys().collect::<Vec<_>>();
+        callsigns.sort();
+        writeln!(out, "# TYPE vpilot_alert_route_progress gauge").unwrap();
+        for callsign in &callsigns {
+            writeln!(
+                out,
+                "vpilot_alert_route_progress{{callsign=\"{callsign}\"}} {}",
+                stats[*callsign].route_progress
+            )
+            .unwrap();
+        }
+        writeln!(out, "# TYPE vpilot_alert_route_deviation_nm gauge").unwrap();
+        for callsign in &callsigns {
+            writeln!(
+                out,
+                "vpilot_alert_route_deviation_nm{{callsign=\"{callsign}\"}} {}",
+                stats[*callsign].route_deviation
+            )
+            .unwrap();
+        }
+        out
+    }
+}
@@ -0,0 +1,130 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use eyre::{bail, Context, Result};
+use reqwest::Client;
+use serde_json::json;
+
+use crate::state::NotificationType;
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(
+        &self,
+        message: &str,
+        kind: &NotificationType,
+        trigger_alarm: bool,
+    ) -> Result<()>;
+
+    /// Whether to keep re-sending an alarm until the app reports it played.
+    fn repeats_alarm(&self) -> bool {
+        true
+    }
+}
+
+pub struct HttpWebhook {
+    url: String,
+    client: Client,
+}
+
+impl HttpWebhook {
+    pub fn new(url: String, client: Client) -> HttpWebhook {
+        HttpWebhook { url, client }
+    }
+}
+
+#[async_trait]
+impl Notifier for HttpWebhook {
+    async fn notify(
+        &self,
+        message: &str,
+        kind: &NotificationType,
+        trigger_alarm: bool,
+    ) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "title": kind.title(),
+                "message": message,
+                "type": kind,
+                "severity": kind.severity(),
+                "triggerAlarm": trigger_alarm,
+            }))
+            .send()
+            .await
+            .context("Webhook HTTP request")?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Failed to send webhook: {}",
+                response.text().await.context("HTTP response text")?
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct DiscordWebhook {
+    url: String,
+    client: Client,
+}
+
+impl DiscordWebhook {
+    pub fn new(url: String, client: Client) -> DiscordWebhook {
+        DiscordWebhook { url, client }
+    }
+}
+
+fn embed_color(kind: &NotificationType) -> u32 {
+    match kind {
+        NotificationType::PrivateMessage => 0x5865f2,
+        NotificationType::RadioMessage => 0x57f287,
+        NotificationType::SelcalAlert => 0xfee75c,
+        NotificationType::CrashDetect => 0xed4245,
+        NotificationType::BackOnRoute => 0x99aab5,
+        NotificationType::NetworkLost => 0xed4245,
+        NotificationType::NetworkRestored => 0x57f287,
+        NotificationType::RouteAmended => 0x99aab5,
+        NotificationType::OffRouteTrend => 0xfee75c,
+        NotificationType::TopOfDescent => 0x5865f2,
+        NotificationType::WaypointApproaching => 0x5865f2,
+        NotificationType::EmergencySquawk => 0xed4245,
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordWebhook {
+    async fn notify(
+        &self,
+        message: &str,
+        kind: &NotificationType,
+        _trigger_alarm: bool,
+    ) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "embeds": [{
+                    "title": kind.title(),
+                    "description": message,
+                    "color": embed_color(kind),
+                    "timestamp": Utc::now().to_rfc3339(),
+                }]
+            }))
+            .send()
+            .await
+            .context("Discord webhook HTTP request")?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Failed to send Discord webhook: {}",
+                response.text().await.context("HTTP response text")?
+            );
+        }
+        Ok(())
+    }
+
+    fn repeats_alarm(&self) -> bool {
+        false
+    }
+}
@@ -1,21 +1,36 @@
 use std::{
-    collections::{HashMap, HashSet},
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, OnceLock,
+    },
     thread,
     time::{Duration, Instant},
 };
 
-use chrono::{Local, Utc};
-use eyre::{bail, Context, ContextCompat, Result};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use eyre::{bail, eyre, Context, ContextCompat, Result};
 use flume::{bounded, Receiver, Sender};
 use geo::{
     Bearing, Closest, Destination, Distance, Haversine, HaversineClosestPoint, Intersects, Line,
     Point,
 };
 use regex::Regex;
-use reqwest::get;
-use rusqlite::Connection;
+use reqwest::Client;
+use rusqlite::{Connection, OpenFlags};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use serde_json::json;
+use tokio::{sync::Mutex, time::sleep};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    events::{Event, EventLog},
+    state::CrashThresholds,
+    store::{StateKey, StateStore},
+};
 
 #[derive(Debug, Clone, Deserialize)]
 struct VatsimData {
@@ -24,12 +39,18 @@ struct VatsimData {
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Pilot {
+    #[serde(default)]
+    pub cid: u64,
     pub callsign: String,
     pub latitude: f64,
     pub longitude: f64,
     pub altitude: i64,
     #[serde(rename = "groundspeed")]
     pub ground_speed: i64,
+    #[serde(default)]
+    pub heading: i64,
+    #[serde(default)]
+    pub transponder: String,
     pub flight_plan: Option<FlightPlan>,
 }
 
@@ -39,6 +60,50 @@ pub struct FlightPlan {
     pub arrival: String,
     pub route: String,
     pub enroute_time: String,
+    #[serde(default)]
+    pub altitude: String,
+    #[serde(default)]
+    pub aircraft_short: String,
+    /// Full equipment string, eg. `B77W/H-SDE3FGHIJ2J3J4J5M1RWXY/LB1D1`
+    #[serde(default)]
+    pub aircraft: String,
+    #[serde(default)]
+    pub remarks: String,
+}
+
+impl Pilot {
+    /// What the transponder code means when it is one of the emergency codes.
+    pub fn emergency_squawk(&self) -> Option<&'static str> {
+        match self.transponder.trim() {
+            "7700" => Some("general emergency"),
+            "7600" => Some("radio failure"),
+            "7500" => Some("hijack"),
+            _ => None,
+        }
+    }
+}
+
+impl FlightPlan {
+    /// ICAO type designator, taken from the equipment string when `aircraft_short` is missing.
+    pub fn aircraft_type(&self) -> &str {
+        if !self.aircraft_short.is_empty() {
+            return &self.aircraft_short;
+        }
+        // Both `B77W/H-SDE..` and the older `H/B77W/L` formats
+        self.aircraft
+            .split('/')
+            .find(|part| part.len() > 1)
+            .and_then(|part| part.split('-').next())
+            .unwrap_or_default()
+    }
+
+    /// Registration from the `REG/` remark.
+    pub fn registration(&self) -> Option<&str> {
+        self.remarks
+            .split_whitespace()
+            .find_map(|item| item.strip_prefix("REG/"))
+            .filter(|reg| !reg.is_empty())
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -64,43 +129,380 @@ impl Waypoint {
 
 pub struct Route {
     callsign: String,
+    nav_db_paths: Vec<String>,
+    cid: Option<u64>,
     current_route: Vec<String>,
     previous_route: Vec<String>,
-    last_vatsim_update: Instant,
     route_waypoints: Vec<Waypoint>,
     aircraft_waypoints: Vec<Waypoint>,
     last_waypoint_count: usize,
     last_stat: RouteStatistics,
     tx: Sender<RouteRequest>,
-    rx: Receiver<Result<Vec<Waypoint>>>,
+    rx: Receiver<RouteResponse>,
     weather_cache: HashMap<String, WeatherData>,
+    events: EventLog,
+    thresholds: CrashThresholds,
+    store: StateStore,
+    last_segment: Option<usize>,
+    db_versions: Vec<usize>,
+    navdb_cycle: String,
+    eta_model: EtaModel,
+    client: Client,
+    route_override: Option<FlightPlan>,
+    route_revision: u64,
+    route_amendment: Option<String>,
+    arrival_resolved: bool,
+    track: Vec<TrackPoint>,
+    track_log: EventLog,
+    legs: Vec<LegEstimate>,
+    sim_rate: f64,
+    /// Departure and arrival airports, or the ends of the route when missing from the nav db
+    airports: Vec<Waypoint>,
+    has_flight_plan: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub altitude: i64,
+    pub ground_speed: i64,
+    pub epoch_ms: i64,
+}
+
+/// Estimate for one remaining leg of the route.
+#[derive(Debug, Clone, Serialize)]
+pub struct LegEstimate {
+    pub from: String,
+    pub to: String,
+    pub dist_nm: f64,
+    pub gs: f64,
+    pub eta: String,
+    pub eta_epoch_ms: i64,
+    /// Seconds from now until `to` is reached
+    pub remaining_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EtaModel {
+    /// Cruise Mach for every leg
+    Mach,
+    /// Reported ground speed below cruise altitude, blending into cruise Mach
+    Blended,
+}
+
+/// Positions to look back over when inferring the sim rate, long enough to smooth out feed update jitter.
+const SIM_RATE_WINDOW: usize = 8;
+
+/// Below this ground speed the distance covered says little about time acceleration.
+const SIM_RATE_MIN_GROUND_SPEED: i64 = 50;
+
+/// Time acceleration as a whole multiple, from the distance covered over the last few positions
+/// compared with the reported ground speed, which stays in real knots.
+fn estimate_sim_rate(track: &[TrackPoint]) -> Option<f64> {
+    let window = &track[track.len().saturating_sub(SIM_RATE_WINDOW + 1)..];
+    let (first, last) = (window.first()?, window.last()?);
+    let hours = (last.epoch_ms - first.epoch_ms) as f64 / 3_600_000.0;
+    let ground_speed =
+        window.iter().map(|p| p.ground_speed).sum::<i64>() as f64 / window.len() as f64;
+    if hours <= 0.0 || ground_speed < SIM_RATE_MIN_GROUND_SPEED as f64 {
+        return None;
+    }
+
+    let distance_nm = window
+        .windows(2)
+        .map(|pair| {
+            Haversine.distance(
+                Point::new(pair[0].lon, pair[0].lat),
+                Point::new(pair[1].lon, pair[1].lat),
+            )
+        })
+        .sum::<f64>()
+        / 1852.0;
+    Some((distance_nm / hours / ground_speed).round().max(1.0))
+}
+
+/// Descent distance for every 1000ft, the 3:1 rule.
+const DESCENT_NM_PER_1000FT: f64 = 3.0;
+
+fn descent_distance_nm(cruise_altitude: i64) -> f64 {
+    cruise_altitude.max(0) as f64 / 1000.0 * DESCENT_NM_PER_1000FT
 }
 
+/// Distance over which the reported ground speed blends into the cruise estimate.
+const ETA_BLEND_DISTANCE_NM: f64 = 100.0;
+
 struct WeatherData {
     last_update: Instant,
-    ws: f64,
-    wd: f64,
-    temp_k: f64,
+    level: u32,
+    start: Option<DateTime<Utc>>,
+    hourly: GfsHourly,
+}
+
+impl WeatherData {
+    fn at(&self, time: DateTime<Utc>) -> (f64, f64, f64) {
+        let idx = self
+            .start
+            .map(|start| (time - start).num_hours())
+            .filter(|&h| h >= 0 && (h as usize) < self.hourly.len())
+            .unwrap_or(0) as usize;
+        (
+            self.hourly.wind_speed[idx],
+            self.hourly.wind_direction[idx],
+            checked_temperature(self.hourly.temperature[idx] + 273.15, self.level),
+        )
+    }
+}
+
+enum RouteRequest {
+    Waypoints {
+        route_tokens: Vec<String>,
+        flight_plan: FlightPlan,
+    },
+    Fix(String),
+    Airports(Vec<String>),
+}
+
+enum RouteResponse {
+    Waypoints(Result<Vec<Waypoint>>),
+    Fix(Result<FixLookup>),
+    Airports(Vec<Option<Waypoint>>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FixCandidate {
+    #[serde(flatten)]
+    pub waypoint: Waypoint,
+    pub source: &'static str,
+    pub db_version: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FixLookup {
+    pub db_versions: Vec<usize>,
+    pub candidates: Vec<FixCandidate>,
+}
+
+#[derive(Debug)]
+pub struct PilotNotConnected;
+
+impl std::fmt::Display for PilotNotConnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pilot not yet connected to vatsim!")
+    }
+}
+
+impl std::error::Error for PilotNotConnected {}
+
+#[derive(Debug)]
+pub struct NoFlightPlan;
+
+impl std::fmt::Display for NoFlightPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pilot has no flight plan")
+    }
+}
+
+impl std::error::Error for NoFlightPlan {}
+
+#[derive(Debug, Clone)]
+pub struct VatsimUnavailable {
+    reason: String,
+    cached: bool,
+}
+
+impl VatsimUnavailable {
+    /// True for a failure another caller already fetched and reported during this poll.
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+}
+
+impl std::fmt::Display for VatsimUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Vatsim data unavailable: {}", self.reason)
+    }
+}
+
+impl std::error::Error for VatsimUnavailable {}
+
+pub const DEFAULT_VATSIM_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+static VATSIM_POLL_INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// Poll the vatsim data feed every `interval` instead of every 15 seconds.
+pub fn set_vatsim_poll_interval(interval: Duration) -> Result<()> {
+    VATSIM_POLL_INTERVAL
+        .set(interval)
+        .map_err(|_| eyre!("Vatsim poll interval already set"))
+}
+
+/// How long a vatsim snapshot is used before fetching a new one.
+pub fn vatsim_poll_interval() -> Duration {
+    VATSIM_POLL_INTERVAL
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_VATSIM_POLL_INTERVAL)
+}
+
+static VATSIM_DATA: Mutex<Option<(Instant, Arc<VatsimData>)>> = Mutex::const_new(None);
+/// The last failed download, so other callers do not all retry it until the next poll.
+static VATSIM_FAILURE: Mutex<Option<(Instant, VatsimUnavailable)>> = Mutex::const_new(None);
+
+async fn fetch_vatsim_data(client: &Client) -> Result<Arc<VatsimData>> {
+    let mut cache = VATSIM_DATA.lock().await;
+    if let Some((fetched_at, data)) = cache.as_ref() {
+        if fetched_at.elapsed() < vatsim_poll_interval() {
+            return Ok(data.clone());
+        }
+    }
+    let mut failure = VATSIM_FAILURE.lock().await;
+    if let Some((failed_at, err)) = failure.as_ref() {
+        if failed_at.elapsed() < vatsim_poll_interval() {
+            return Err(VatsimUnavailable {
+                cached: true,
+                ..err.clone()
+            }
+            .into());
+        }
+    }
+
+    let data = match REPLAY.get() {
+        Some(replay) => match replay.lock().await.pop_front() {
+            Some(snapshot) => Arc::new(
+                serde_json::from_str::<VatsimData>(&snapshot)
+                    .context("Parse replayed vatsim snapshot")?,
+            ),
+            // Hold the final snapshot once the replay runs out
+            None => cache
+                .as_ref()
+                .map(|(_, data)| data.clone())
+                .context("Vatsim replay is empty")?,
+        },
+        None => match download_vatsim_data(client).await {
+            Ok(data) => Arc::new(data),
+            Err(err) => {
+                *failure = Some((Instant::now(), err.clone()));
+                return Err(err.into());
+            }
+        },
+    };
+    *failure = None;
+    *cache = Some((Instant::now(), data.clone()));
+    Ok(data)
+}
+
+pub const DEFAULT_VATSIM_URL: &str = "https://data.vatsim.net/v3/vatsim-data.json";
+
+pub struct VatsimSource {
+    pub url: String,
+    pub bearer_token: Option<String>,
+}
+
+static VATSIM_SOURCE: OnceLock<VatsimSource> = OnceLock::new();
+
+/// Fetch vatsim data from `source` instead of the official feed.
+pub fn set_vatsim_source(source: VatsimSource) -> Result<()> {
+    VATSIM_SOURCE
+        .set(source)
+        .map_err(|_| eyre!("Vatsim source already set"))
+}
+
+const VATSIM_FETCH_ATTEMPTS: u32 = 3;
+const VATSIM_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn download_vatsim_data(client: &Client) -> Result<VatsimData, VatsimUnavailable> {
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            let source = VATSIM_SOURCE.get();
+            let mut request = client
+                .get(source.map_or(DEFAULT_VATSIM_URL, |s| s.url.as_str()))
+                .timeout(VATSIM_FETCH_TIMEOUT);
+            if let Some(token) = source.and_then(|s| s.bearer_token.as_ref()) {
+                request = request.bearer_auth(token);
+            }
+            let response = request.send().await?.error_for_status()?;
+            response.json::<VatsimData>().await
+        }
+        .await;
+
+        match result {
+            Ok(data) => return Ok(data),
+            Err(err) if attempt < VATSIM_FETCH_ATTEMPTS => {
+                debug!("Vatsim fetch attempt {attempt} failed: {err}");
+                sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1))).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(VatsimUnavailable {
+                    reason: err.to_string(),
+                    cached: false,
+                })
+            }
+        }
+    }
 }
 
-struct RouteRequest {
-    route_tokens: Vec<String>,
-    flight_plan: FlightPlan,
+static NAT_LOOKUP_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Never call nattrak for `NATx` route tokens, the route then relies on its own coordinates.
+pub fn disable_nat_lookup() {
+    NAT_LOOKUP_DISABLED.store(true, Ordering::Relaxed);
 }
 
-async fn get_vatsim_data(callsign: &str) -> Result<Pilot> {
-    let response = get("https://data.vatsim.net/v3/vatsim-data.json").await?;
-    if !response.status().is_success() {
-        bail!("Failed to fetch vatsim data");
+static REPLAY: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
+
+/// Serve vatsim data from recorded snapshots instead of the live feed, advancing one per poll.
+/// `path` is either a file with one snapshot per line, or a directory of snapshot files read in name order.
+pub fn start_replay(path: &Path) -> Result<()> {
+    let snapshots = if path.is_dir() {
+        let mut files = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.sort();
+        files
+            .iter()
+            .map(|file| {
+                std::fs::read_to_string(file)
+                    .with_context(|| format!("Read vatsim snapshot {}", file.display()))
+            })
+            .collect::<Result<VecDeque<_>>>()?
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Read vatsim replay {}", path.display()))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+    if snapshots.is_empty() {
+        bail!("No vatsim snapshots in {}", path.display());
     }
 
-    let vatsim_data: VatsimData = response.json().await?;
+    debug!("Replaying {} vatsim snapshots", snapshots.len());
+    REPLAY
+        .set(Mutex::new(snapshots))
+        .map_err(|_| eyre!("Vatsim replay already started"))
+}
+
+async fn get_vatsim_data(client: &Client, callsign: &str, cid: Option<u64>) -> Result<Pilot> {
+    let vatsim_data = fetch_vatsim_data(client).await?;
     let pilot = vatsim_data
         .pilots
         .iter()
-        .position(|p| p.callsign == callsign);
+        .position(|p| p.callsign == callsign)
+        .or_else(|| {
+            let cid = cid?;
+            let pilot = vatsim_data.pilots.iter().position(|p| p.cid == cid)?;
+            debug!(
+                "{callsign} not found, using {} connected as CID {cid}",
+                vatsim_data.pilots[pilot].callsign
+            );
+            Some(pilot)
+        });
     if pilot.is_none() {
-        bail!("Pilot not yet connected to vatsim!");
+        return Err(PilotNotConnected.into());
     }
     Ok(vatsim_data.pilots[pilot.unwrap()].clone())
 }
@@ -111,44 +513,86 @@ pub struct RouteStatistics {
     pub next_waypoint: String,
     pub prev_waypoint: String,
     pub route_deviation: f64,
+    /// Deviation grew over the last few positions, at `deviation_rate` nm per minute
+    pub deviation_increasing: bool,
+    pub deviation_rate: f64,
     pub route_progress: f64,
     pub dist_next_wp: f64,
+    /// True bearing from the aircraft to the next waypoint
+    pub bearing_next_wp: f64,
+    /// `left` or `right` of the route segment, looking along it
+    pub cross_track_side: String,
+    pub dist_remaining_nm: f64,
+    /// Distance to the top of descent by the 3:1 rule from the filed cruise altitude, negative once past it
+    pub dist_to_tod_nm: f64,
+    /// Distance to the nearer of the departure and arrival airports
+    pub dist_to_airport_nm: f64,
     pub in_loop: bool,
     pub stuck: bool,
     pub pilot: Pilot,
+    /// `unavailable` when the weather could not be fetched, `eta_epoch_ms` and `remaining_seconds` are then 0
     pub eta: String,
+    pub eta_epoch_ms: i64,
+    pub remaining_seconds: u64,
+    pub aircraft_type: String,
+    pub registration: Option<String>,
+    pub navdb_cycle: String,
+    /// False when the arrival airport is missing from the nav db and the route ends at the last fix
+    pub arrival_resolved: bool,
+    /// Time acceleration inferred from the distance covered against the reported ground speed
+    pub sim_rate: f64,
 }
 
 impl Route {
-    pub fn new(nav_db: &str, callsign: &str) -> Result<Route> {
-        let conn = Connection::open(nav_db).context("Could not open nav db")?;
-        let (tx, rx) = bounded(1);
-        let (tx_r, rx_r) = bounded(1);
-        let ir = InnerRoute::new(conn)?;
-        thread::spawn(move || InnerRoute::start(ir, tx_r, rx));
+    pub fn new(
+        nav_dbs: &[String],
+        callsign: &str,
+        events: EventLog,
+        thresholds: CrashThresholds,
+        store: StateStore,
+        eta_model: EtaModel,
+        client: Client,
+    ) -> Result<Route> {
+        let worker = spawn_nav_db_worker(nav_dbs)?;
 
         Ok(Route {
             callsign: callsign.to_owned(),
+            nav_db_paths: nav_dbs.to_vec(),
+            cid: None,
             current_route: Vec::new(),
             previous_route: Vec::new(),
             route_waypoints: Vec::new(),
             aircraft_waypoints: Vec::new(),
             last_waypoint_count: 0,
-            last_vatsim_update: Instant::now() - Duration::from_secs(16),
             last_stat: RouteStatistics::default(),
-            tx,
-            rx: rx_r,
+            tx: worker.tx,
+            rx: worker.rx,
             weather_cache: HashMap::new(),
+            events,
+            thresholds,
+            store,
+            last_segment: None,
+            db_versions: worker.db_versions,
+            navdb_cycle: worker.cycle,
+            eta_model,
+            client,
+            route_override: None,
+            route_revision: 0,
+            route_amendment: None,
+            arrival_resolved: false,
+            track: Vec::new(),
+            track_log: EventLog::default(),
+            legs: Vec::new(),
+            sim_rate: 1.0,
+            airports: Vec::new(),
+            // Assume one is filed so that a missing plan is logged on the first update
+            has_flight_plan: true,
         })
     }
 
+    /// Fetches the latest VATSIM data, called once per `vatsim_poll_interval`.
     pub async fn route_statistics(&mut self) -> Result<RouteStatistics> {
-        if self.last_vatsim_update.elapsed() < Duration::from_secs(15) {
-            return Ok(self.last_stat.clone());
-        }
-
-        let pilot = get_vatsim_data(&self.callsign).await?;
-        self.last_vatsim_update = Instant::now();
+        let pilot = get_vatsim_data(&self.client, &self.callsign, self.cid).await?;
 
         let mut stuck = false;
         if self.aircraft_waypoints.len() == 120 {
@@ -161,71 +605,81 @@ impl Route {
                 self.last_waypoint_count = 0;
                 self.aircraft_waypoints
                     .push(Waypoint::unknown(pilot.latitude, pilot.longitude));
+                self.record_track(&pilot);
+                self.sim_rate = estimate_sim_rate(&self.track).unwrap_or(1.0);
             }
 
-            stuck = self.last_waypoint_count > 10;
+            // Give accelerated sims proportionally longer before flagging them as stuck
+            stuck = self.last_waypoint_count as f64
+                > self.thresholds.stuck_count as f64 * self.sim_rate;
         } else {
             self.aircraft_waypoints
                 .push(Waypoint::unknown(pilot.latitude, pilot.longitude));
+            self.record_track(&pilot);
         }
 
         self.previous_route = self.current_route.clone();
-        let flight_plan = pilot
-            .flight_plan
-            .as_ref()
-            .context("Pilot has no flight plan")?;
-        self.current_route = flight_plan
-            .route
-            .split_whitespace()
-            .filter(|s| s.to_uppercase() != "DCT")
-            .map(|x| x.to_owned())
-            .collect::<Vec<_>>();
+        let flight_plan = match (&self.route_override, &pilot.flight_plan) {
+            (Some(flight_plan), _) | (None, Some(flight_plan)) => flight_plan.clone(),
+            (None, None) => {
+                if self.has_flight_plan {
+                    warn!(
+                        "{} is connected without a flight plan, waiting for one to be filed",
+                        self.callsign
+                    );
+                    self.has_flight_plan = false;
+                }
+                return Err(NoFlightPlan.into());
+            }
+        };
+        if !self.has_flight_plan {
+            info!("Flight plan filed for {}", self.callsign);
+            self.has_flight_plan = true;
+        }
+        self.current_route = route_tokens(&flight_plan.route);
 
         if self.current_route.len() != self.previous_route.len()
             || md5::compute(self.current_route.join(""))
                 != md5::compute(self.previous_route.join(""))
+            || self.route_waypoints.is_empty()
         {
-            self.tx
-                .send_async(RouteRequest {
-                    route_tokens: self.current_route.clone(),
-                    flight_plan: flight_plan.clone(),
-                })
-                .await?;
-            self.route_waypoints = self.rx.recv_async().await??;
-
-            debug!("recomputing route waypoints");
-            debug!("FP route: {:#?}", flight_plan.route);
-            debug!(
-                "route: {:#?}",
-                self.route_waypoints
-                    .iter()
-                    .map(|w| w.id.as_str())
-                    .collect::<Vec<_>>()
-                    .join(" -> ")
-            );
-        }
-
-        if self.current_route.len() < 2 {
-            bail!("Route is too short");
+            if !self.previous_route.is_empty() {
+                self.route_amendment = Some(route_diff(&self.previous_route, &self.current_route));
+            }
+            self.load_waypoints(&flight_plan).await?;
         }
 
-        let in_loop = has_loop(&self.aircraft_waypoints);
+        let airports = self.airports.iter().collect::<Vec<_>>();
+        let in_loop = has_loop(&self.aircraft_waypoints, &self.thresholds, &airports);
+        let aircraft = Waypoint::unknown(pilot.latitude, pilot.longitude);
+        let dist_to_airport_nm = airports
+            .iter()
+            .map(|apt| route_length_nm(&[(*apt).clone(), aircraft.clone()]))
+            .fold(f64::INFINITY, f64::min);
         if in_loop {
-            tokio::fs::write(
-                "loops.json",
-                serde_json::to_string_pretty(&self.aircraft_waypoints)?,
-            )
-            .await?;
+            self.store
+                .save(
+                    StateKey::Loops,
+                    &serde_json::to_string_pretty(&self.aircraft_waypoints)?,
+                )
+                .await?;
         }
 
-        let (prev_idx, _, prev, next, segment_deviation) =
-            find_closest_segment(&self.route_waypoints, pilot.latitude, pilot.longitude)
-                .context("Failed to find closest segment")?;
+        let (prev_idx, _, prev, next, segment_deviation) = find_closest_segment(
+            &self.route_waypoints,
+            pilot.latitude,
+            pilot.longitude,
+            self.last_segment,
+        )
+        .context("Failed to find closest segment")?;
+        self.last_segment = Some(prev_idx);
 
         let current_pos = Point::new(pilot.longitude, pilot.latitude);
         let next_pos = Point::new(next.lon, next.lat);
 
         let distance_to_next = Haversine.distance(current_pos, next_pos);
+        let bearing_next_wp = Haversine.bearing(current_pos, next_pos).rem_euclid(360.0);
+        let cross_track_side = cross_track_side(&prev, &next, current_pos);
         let total_distance = route_length_nm(&self.route_waypoints);
 
         let mut done = route_length_nm(&self.route_waypoints[0..prev_idx]);
@@ -248,27 +702,332 @@ impl Route {
             .cloned()
             .collect::<Vec<_>>();
         leftover_wpts.insert(0, Waypoint::unknown(pilot.latitude, pilot.longitude));
-        let eta = self.calculate_eta(leftover_wpts).await?;
+        let cruise_altitude = pilot
+            .flight_plan
+            .as_ref()
+            .and_then(|fp| parse_cruise_altitude(&fp.altitude))
+            .unwrap_or(pilot.altitude);
+        let mach = cruise_mach(
+            pilot
+                .flight_plan
+                .as_ref()
+                .map(FlightPlan::aircraft_type)
+                .unwrap_or_default(),
+        );
+        // Below cruise the reported ground speed says more than the cruise Mach
+        let climb_gs = match self.eta_model {
+            EtaModel::Blended
+                if pilot.altitude < cruise_altitude - 1000 && pilot.ground_speed > 0 =>
+            {
+                Some(pilot.ground_speed as f64)
+            }
+            _ => None,
+        };
+        // A weather outage should not cost the rest of the statistics
+        let (eta, eta_epoch_ms, remaining_seconds) = match self
+            .calculate_eta(
+                leftover_wpts,
+                pressure_level(cruise_altitude),
+                mach,
+                climb_gs,
+            )
+            .await
+        {
+            Ok(legs) => {
+                let remaining_seconds = legs.last().map_or(0, |leg| leg.remaining_seconds);
+                let eta = Utc::now() + Duration::from_secs(remaining_seconds);
+                self.legs = legs;
+                (format_eta(eta), eta.timestamp_millis(), remaining_seconds)
+            }
+            Err(err) => {
+                warn!("Failed to calculate ETA for {}: {err}", self.callsign);
+                self.legs.clear();
+                ("unavailable".to_owned(), 0, 0)
+            }
+        };
+
+        if !self.last_stat.prev_waypoint.is_empty() && self.last_stat.prev_waypoint != prev.id {
+            self.events
+                .record(Event::WaypointPassed { waypoint: &prev.id });
+        }
 
+        let dist_remaining_nm = (total_distance - done).max(0.0);
+        // From the filed plan, a route override has no aircraft details
+        let (aircraft_type, registration) = pilot
+            .flight_plan
+            .as_ref()
+            .map(|fp| {
+                (
+                    fp.aircraft_type().to_owned(),
+                    fp.registration().map(str::to_owned),
+                )
+            })
+            .unwrap_or_default();
         self.last_stat = RouteStatistics {
             leftover_route: leftover,
             next_waypoint: next.id,
             prev_waypoint: prev.id,
             route_deviation: mt_to_nmi(segment_deviation),
+            // Filled in from the deviation history kept by the state loop
+            deviation_increasing: false,
+            deviation_rate: 0.0,
             route_progress: pct_complete,
             dist_next_wp: mt_to_nmi(distance_to_next),
+            bearing_next_wp,
+            cross_track_side: cross_track_side.to_owned(),
+            dist_remaining_nm,
+            dist_to_tod_nm: dist_remaining_nm - descent_distance_nm(cruise_altitude),
+            dist_to_airport_nm,
             in_loop,
             stuck,
             pilot,
             eta,
+            eta_epoch_ms,
+            remaining_seconds,
+            arrival_resolved: self.arrival_resolved,
+            aircraft_type,
+            registration,
+            navdb_cycle: self.navdb_cycle.clone(),
+            sim_rate: self.sim_rate,
         };
 
         Ok(self.last_stat.clone())
     }
 
-    async fn calculate_eta(&mut self, route: Vec<Waypoint>) -> Result<String> {
+    /// Expands `current_route` through the nav db into `route_waypoints`.
+    async fn load_waypoints(&mut self, flight_plan: &FlightPlan) -> Result<()> {
+        self.tx
+            .send_async(RouteRequest::Waypoints {
+                route_tokens: self.current_route.clone(),
+                flight_plan: flight_plan.clone(),
+            })
+            .await?;
+        let waypoints = match self.rx.recv_async().await? {
+            RouteResponse::Waypoints(waypoints) => waypoints?,
+            _ => bail!("Unexpected nav db response"),
+        };
+        if waypoints.len() < 2 {
+            // Loaded again on the next poll instead of monitoring the previous route
+            self.route_waypoints.clear();
+            bail!("Route is too short");
+        }
+        self.route_waypoints = waypoints;
+
+        // The route starts at the first SID or route fix, which can be well away from the airport
+        self.tx
+            .send_async(RouteRequest::Airports(vec![
+                flight_plan.departure.clone(),
+                flight_plan.arrival.clone(),
+            ]))
+            .await?;
+        let RouteResponse::Airports(airports) = self.rx.recv_async().await? else {
+            bail!("Unexpected nav db response");
+        };
+        let ends = [self.route_waypoints.first(), self.route_waypoints.last()];
+        self.airports = airports
+            .into_iter()
+            .zip(ends)
+            .filter_map(|(airport, end)| airport.or(end.cloned()))
+            .collect();
+        self.last_segment = None;
+        self.route_revision += 1;
+        self.arrival_resolved = self
+            .route_waypoints
+            .last()
+            .is_some_and(|wpt| wpt.id == flight_plan.arrival);
+        if !self.arrival_resolved {
+            warn!(
+                "Arrival airport {} not found in the nav db, the route ends at the last fix",
+                flight_plan.arrival
+            );
+        }
+
+        debug!("recomputing route waypoints");
+        debug!("FP route: {:#?}", flight_plan.route);
+        debug!(
+            "route: {:#?}",
+            self.route_waypoints
+                .iter()
+                .map(|w| w.id.as_str())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        );
+        self.events.record(Event::RouteRecomputed {
+            route: &flight_plan.route,
+            waypoints: self.route_waypoints.len(),
+        });
+        Ok(())
+    }
+
+    /// Opens `nav_dbs`, or the current databases again when empty, on a worker that is not used
+    /// until it is passed to `swap_nav_db`.
+    pub fn open_nav_db(&self, nav_dbs: &[String]) -> Result<NavDbWorker> {
+        if nav_dbs.is_empty() {
+            spawn_nav_db_worker(&self.nav_db_paths)
+        } else {
+            spawn_nav_db_worker(nav_dbs)
+        }
+    }
+
+    /// Switches to a worker from `open_nav_db`, the route is recomputed against it on the next poll.
+    pub fn swap_nav_db(&mut self, worker: NavDbWorker) {
+        // Dropping the old sender ends the old worker once it is done with its last request
+        self.tx = worker.tx;
+        self.rx = worker.rx;
+        self.db_versions = worker.db_versions;
+        self.navdb_cycle = worker.cycle;
+        self.nav_db_paths = worker.paths;
+
+        self.current_route.clear();
+    }
+
+    /// Monitors this flight plan instead of the one filed on VATSIM, returns the expanded waypoints.
+    pub async fn set_route_override(&mut self, flight_plan: FlightPlan) -> Result<Vec<Waypoint>> {
+        self.current_route = route_tokens(&flight_plan.route);
+        self.load_waypoints(&flight_plan).await?;
+        self.route_override = Some(flight_plan);
+        Ok(self.route_waypoints.clone())
+    }
+
+    pub fn clear_route_override(&mut self) {
+        if self.route_override.take().is_some() {
+            self.current_route.clear();
+        }
+    }
+
+    /// Fall back to the pilot connected with this VATSIM CID when the callsign is not found.
+    pub fn with_cid(mut self, cid: Option<u64>) -> Self {
+        self.cid = cid;
+        self
+    }
+
+    /// Also append every new position to `track_log`.
+    pub fn with_track_log(mut self, track_log: EventLog) -> Self {
+        self.track_log = track_log;
+        self
+    }
+
+    fn record_track(&mut self, pilot: &Pilot) {
+        let point = TrackPoint {
+            lat: pilot.latitude,
+            lon: pilot.longitude,
+            altitude: pilot.altitude,
+            ground_speed: pilot.ground_speed,
+            epoch_ms: Utc::now().timestamp_millis(),
+        };
+        self.track_log.record(Event::Position {
+            callsign: &self.callsign,
+            point: &point,
+        });
+        self.track.push(point);
+    }
+
+    /// Every position recorded since startup, unlike the last 120 kept for loop detection.
+    pub fn track(&self) -> &[TrackPoint] {
+        &self.track
+    }
+
+    /// Tokens added and removed by the last change to the filed route, until taken.
+    pub fn take_route_amendment(&mut self) -> Option<String> {
+        self.route_amendment.take()
+    }
+
+    /// Per-leg estimates from the last statistics update, empty when the ETA is unavailable.
+    pub fn legs(&self) -> &[LegEstimate] {
+        &self.legs
+    }
+
+    /// False while the pilot is connected without a flight plan.
+    pub fn has_flight_plan(&self) -> bool {
+        self.has_flight_plan
+    }
+
+    /// Increases every time the route waypoints are recomputed.
+    pub fn route_revision(&self) -> u64 {
+        self.route_revision
+    }
+
+    pub fn current_waypoints(&self) -> Vec<Waypoint> {
+        self.aircraft_waypoints
+            .last()
+            .into_iter()
+            .chain(self.route_waypoints.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn to_geojson(&self) -> serde_json::Value {
+        let coords = |wpts: &[Waypoint]| {
+            wpts.iter()
+                .map(|wpt| json!([wpt.lon, wpt.lat]))
+                .collect::<Vec<_>>()
+        };
+
+        let mut features = vec![
+            json!({
+                "type": "Feature",
+                "properties": { "name": "route" },
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coords(&self.route_waypoints),
+                },
+            }),
+            json!({
+                "type": "Feature",
+                "properties": { "name": "track", "in_loop": self.last_stat.in_loop },
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coords(&self.aircraft_waypoints),
+                },
+            }),
+        ];
+        if let Some(position) = self.aircraft_waypoints.last() {
+            features.push(json!({
+                "type": "Feature",
+                "properties": { "name": self.callsign },
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [position.lon, position.lat],
+                },
+            }));
+        }
+
+        json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })
+    }
+
+    pub fn db_versions(&self) -> &[usize] {
+        &self.db_versions
+    }
+
+    /// AIRAC cycle of the first nav db with a header, `unknown` if none has one.
+    pub fn navdb_cycle(&self) -> &str {
+        &self.navdb_cycle
+    }
+
+    pub async fn lookup_fix(&self, ident: &str) -> Result<FixLookup> {
+        self.tx
+            .send_async(RouteRequest::Fix(ident.to_uppercase()))
+            .await?;
+        match self.rx.recv_async().await? {
+            RouteResponse::Fix(lookup) => lookup,
+            _ => bail!("Unexpected nav db response"),
+        }
+    }
+
+    async fn calculate_eta(
+        &mut self,
+        route: Vec<Waypoint>,
+        level: u32,
+        mach: f64,
+        climb_gs: Option<f64>,
+    ) -> Result<Vec<LegEstimate>> {
+        let now = Utc::now();
         let mut total_seconds = 0f64;
-        let mach = 0.86;
+        let mut flown_nm = 0f64;
+        let mut legs = Vec::new();
 
         for pair in route.windows(2) {
             let a = Point::new(pair[0].lon, pair[0].lat);
@@ -278,65 +1037,232 @@ impl Route {
             let midpoint = Haversine.destination(a, track, dist / 2.0);
 
             let dist_nm = dist / 1852.0;
-            let (ws, wd, temp_k) = self.fetch_weather(midpoint.y(), midpoint.x()).await?;
+            let leg_time = now + Duration::from_secs(total_seconds.round() as u64);
+            let (ws, wd, temp_k) = self
+                .fetch_weather(midpoint.y(), midpoint.x(), level, leg_time)
+                .await?;
             let tas = mach_to_tas(mach, temp_k);
             let hw = wind_component(ws, wd, track);
-            let gs = tas + hw;
+            let mut gs = tas + hw;
+            if let Some(climb_gs) = climb_gs {
+                // Actual ground speed for the first leg, fading into cruise over the climb
+                let weight = (1.0 - flown_nm / ETA_BLEND_DISTANCE_NM).max(0.0);
+                gs = weight * climb_gs + (1.0 - weight) * gs;
+            }
             let time_h = dist_nm / gs;
-            total_seconds += time_h * 3600.0;
+            total_seconds += time_h * 3600.0 / self.sim_rate;
+            flown_nm += dist_nm;
+
+            let remaining_seconds = total_seconds.round() as u64;
+            let eta = now + Duration::from_secs(remaining_seconds);
+            legs.push(LegEstimate {
+                from: pair[0].id.clone(),
+                to: pair[1].id.clone(),
+                dist_nm,
+                gs,
+                eta: format_eta(eta),
+                eta_epoch_ms: eta.timestamp_millis(),
+                remaining_seconds,
+            });
         }
 
-        let now = Utc::now();
-        let dur = Duration::from_secs(total_seconds.round() as u64);
-        let eta = now + dur;
-        let eta_local = eta.with_timezone(&Local);
-        Ok(eta_local.format("%Y-%m-%d %H:%M %Z").to_string())
+        Ok(legs)
     }
 
-    async fn fetch_weather(&mut self, lat: f64, lon: f64) -> Result<(f64, f64, f64)> {
-        if let Some(weather) = self.weather_cache.get(&format!("{lat},{lon}")) {
+    async fn fetch_weather(
+        &mut self,
+        lat: f64,
+        lon: f64,
+        level: u32,
+        time: DateTime<Utc>,
+    ) -> Result<(f64, f64, f64)> {
+        let lat = round_to_grid(lat);
+        let lon = round_to_grid(lon);
+        let key = format!("{lat:.1},{lon:.1}@{level}");
+        if let Some(weather) = self.weather_cache.get(&key) {
             if weather.last_update.elapsed() < Duration::from_secs(60 * 30) {
-                return Ok((weather.ws, weather.wd, weather.temp_k));
+                return Ok(weather.at(time));
             }
         }
 
-        let url = format!("https://api.open-meteo.com/v1/gfs?latitude={lat}&longitude={lon}&hourly=windspeed_250hPa,winddirection_250hPa,temperature_250hPa");
-        let resp = get(&url).await?.json::<GfsResponse>().await?;
-        let ws = resp.hourly.wind_speed_250h_pa[0];
-        let wd = resp.hourly.wind_direction_250h_pa[0];
-        let temp_k = resp.hourly.temperature_250h_pa[0] + 273.15;
-        let entry = self
-            .weather_cache
-            .entry(format!("{lat},{lon}"))
-            .or_insert(WeatherData {
-                last_update: Instant::now(),
-                ws,
-                wd,
-                temp_k,
-            });
-        *entry = WeatherData {
+        let url = format!("https://api.open-meteo.com/v1/gfs?latitude={lat}&longitude={lon}&hourly=windspeed_{level}hPa,winddirection_{level}hPa,temperature_{level}hPa");
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .json::<GfsResponse>()
+            .await?;
+        let hourly = GfsHourly::from_response(resp.hourly, level)?;
+        if hourly.len() == 0 {
+            bail!("No hourly weather data for {lat},{lon}");
+        }
+        let start = hourly
+            .time
+            .first()
+            .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M").ok())
+            .map(|t| t.and_utc());
+        let weather = WeatherData {
             last_update: Instant::now(),
-            ws,
-            wd,
-            temp_k,
+            level,
+            start,
+            hourly,
         };
-        Ok((ws, wd, temp_k))
+        let values = weather.at(time);
+        self.weather_cache.insert(key, weather);
+        Ok(values)
     }
 }
 
 #[derive(Deserialize)]
 struct GfsResponse {
-    hourly: GfsHourly,
+    hourly: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Deserialize)]
 struct GfsHourly {
-    #[serde(rename = "windspeed_250hPa")]
-    wind_speed_250h_pa: Vec<f64>,
-    #[serde(rename = "winddirection_250hPa")]
-    wind_direction_250h_pa: Vec<f64>,
-    #[serde(rename = "temperature_250hPa")]
-    temperature_250h_pa: Vec<f64>,
+    time: Vec<String>,
+    wind_speed: Vec<f64>,
+    wind_direction: Vec<f64>,
+    temperature: Vec<f64>,
+}
+
+impl GfsHourly {
+    fn from_response(mut hourly: HashMap<String, serde_json::Value>, level: u32) -> Result<Self> {
+        let mut take = |field: String| -> Result<serde_json::Value> {
+            hourly
+                .remove(&field)
+                .with_context(|| format!("Missing {field} in weather response"))
+        };
+        Ok(GfsHourly {
+            time: serde_json::from_value(take("time".to_owned())?)?,
+            wind_speed: serde_json::from_value(take(format!("windspeed_{level}hPa"))?)?,
+            wind_direction: serde_json::from_value(take(format!("winddirection_{level}hPa"))?)?,
+            temperature: serde_json::from_value(take(format!("temperature_{level}hPa"))?)?,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.wind_speed
+            .len()
+            .min(self.wind_direction.len())
+            .min(self.temperature.len())
+    }
+}
+
+const PRESSURE_LEVELS: [(u32, i64); 5] = [
+    (500, 18000),
+    (400, 24000),
+    (300, 30000),
+    (250, 34000),
+    (200, 39000),
+];
+
+fn parse_cruise_altitude(altitude: &str) -> Option<i64> {
+    match altitude.strip_prefix("FL") {
+        Some(fl) => fl.parse::<i64>().ok().map(|fl| fl * 100),
+        None => altitude.parse().ok(),
+    }
+}
+
+fn pressure_level(altitude: i64) -> u32 {
+    PRESSURE_LEVELS
+        .iter()
+        .min_by_key(|(_, level_altitude)| (altitude - level_altitude).abs())
+        .map(|(level, _)| *level)
+        .unwrap()
+}
+
+/// Plausible temperatures between FL180 and FL390, anything else is a bad reading.
+const TEMPERATURE_RANGE_K: std::ops::RangeInclusive<f64> = 180.0..=320.0;
+
+/// ISA temperature at a pressure level, constant above the tropopause.
+fn isa_temperature(level: u32) -> f64 {
+    let pressure = level as f64;
+    if pressure <= 226.32 {
+        216.65
+    } else {
+        288.15 * (pressure / 1013.25).powf(0.190263)
+    }
+}
+
+fn checked_temperature(temp_k: f64, level: u32) -> f64 {
+    if TEMPERATURE_RANGE_K.contains(&temp_k) {
+        return temp_k;
+    }
+    let isa = isa_temperature(level);
+    warn!("Implausible temperature {temp_k:.1}K at {level}hPa, using ISA {isa:.1}K");
+    isa
+}
+
+const WEATHER_GRID_DEG: f64 = 0.5;
+
+fn round_to_grid(deg: f64) -> f64 {
+    (deg / WEATHER_GRID_DEG).round() * WEATHER_GRID_DEG
+}
+
+const DEFAULT_CRUISE_MACH: f64 = 0.80;
+
+static CRUISE_MACH: LazyLock<HashMap<&'static str, f64>> = LazyLock::new(|| {
+    HashMap::from([
+        ("A19N", 0.78),
+        ("A20N", 0.78),
+        ("A21N", 0.78),
+        ("A319", 0.78),
+        ("A320", 0.78),
+        ("A321", 0.78),
+        ("A332", 0.82),
+        ("A333", 0.82),
+        ("A339", 0.82),
+        ("A343", 0.82),
+        ("A346", 0.83),
+        ("A359", 0.85),
+        ("A35K", 0.85),
+        ("A388", 0.85),
+        ("B38M", 0.79),
+        ("B39M", 0.79),
+        ("B737", 0.78),
+        ("B738", 0.78),
+        ("B739", 0.78),
+        ("B744", 0.85),
+        ("B748", 0.855),
+        ("B752", 0.80),
+        ("B763", 0.80),
+        ("B764", 0.80),
+        ("B772", 0.84),
+        ("B77L", 0.84),
+        ("B77W", 0.84),
+        ("B788", 0.85),
+        ("B789", 0.85),
+        ("B78X", 0.85),
+        ("BCS1", 0.78),
+        ("BCS3", 0.78),
+        ("C25A", 0.64),
+        ("C25B", 0.68),
+        ("C56X", 0.75),
+        ("C68A", 0.78),
+        ("C700", 0.82),
+        ("CRJ7", 0.78),
+        ("CRJ9", 0.78),
+        ("E170", 0.75),
+        ("E175", 0.75),
+        ("E190", 0.78),
+        ("E195", 0.78),
+        ("GLF6", 0.85),
+        ("MD11", 0.82),
+    ])
+});
+
+fn cruise_mach(aircraft_type: &str) -> f64 {
+    CRUISE_MACH
+        .get(aircraft_type.to_uppercase().as_str())
+        .copied()
+        .unwrap_or(DEFAULT_CRUISE_MACH)
+}
+
+fn format_eta(eta: DateTime<Utc>) -> String {
+    eta.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M %Z")
+        .to_string()
 }
 
 fn mach_to_tas(mach: f64, temp_k: f64) -> f64 {
@@ -347,92 +1273,145 @@ fn wind_component(ws: f64, wd: f64, track: f64) -> f64 {
     (wd - track).to_radians().cos() * ws
 }
 
+pub struct NavDbWorker {
+    tx: Sender<RouteRequest>,
+    rx: Receiver<RouteResponse>,
+    db_versions: Vec<usize>,
+    cycle: String,
+    paths: Vec<String>,
+}
+
+/// Opens `nav_dbs` on a new worker thread.
+fn spawn_nav_db_worker(nav_dbs: &[String]) -> Result<NavDbWorker> {
+    let conns = nav_dbs
+        .iter()
+        .map(|path| Connection::open(path).with_context(|| format!("Could not open nav db {path}")))
+        .collect::<Result<Vec<_>>>()?;
+    let (tx, rx) = bounded(1);
+    let (tx_r, rx_r) = bounded(1);
+    let ir = InnerRoute::new(conns)?;
+    let db_versions = ir.dbs.iter().map(|db| db.db_version).collect();
+    let cycle = ir
+        .dbs
+        .iter()
+        .find_map(|db| db.cycle.clone())
+        .unwrap_or_else(|| "unknown".to_owned());
+    thread::spawn(move || InnerRoute::start(ir, tx_r, rx));
+    Ok(NavDbWorker {
+        tx,
+        rx: rx_r,
+        db_versions,
+        cycle,
+        paths: nav_dbs.to_vec(),
+    })
+}
+
 struct InnerRoute {
-    conn: Connection,
-    db_version: usize,
+    dbs: Vec<NavDb>,
+    // Nav data never changes while the databases are open
+    fix_cache: RefCell<HashMap<String, Vec<FixCandidate>>>,
+    airway_cache: RefCell<HashMap<(String, String, String), Vec<Waypoint>>>,
 }
 
 impl InnerRoute {
-    fn new(conn: Connection) -> Result<InnerRoute> {
-        let mut stmt = conn.prepare(
-            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name='tbl_header')",
-        )?;
-        let exists: bool = stmt.query_row([], |row| row.get(0))?;
-        let db_version = if exists { 1 } else { 2 };
-        drop(stmt);
-        debug!("Database version: {db_version}");
-        Ok(InnerRoute { conn, db_version })
+    fn new(conns: Vec<Connection>) -> Result<InnerRoute> {
+        let dbs = conns
+            .into_iter()
+            .map(NavDb::new)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(InnerRoute {
+            dbs,
+            fix_cache: RefCell::new(HashMap::new()),
+            airway_cache: RefCell::new(HashMap::new()),
+        })
     }
 
-    fn start(self, tx: Sender<Result<Vec<Waypoint>>>, rx: Receiver<RouteRequest>) {
-        while let Ok(RouteRequest {
-            route_tokens,
-            flight_plan,
-        }) = rx.recv()
-        {
-            if let Err(err) = tx.send(self.get_waypoints(&route_tokens, &flight_plan)) {
-                error!("Failed to send waypoints: {err}");
+    fn start(self, tx: Sender<RouteResponse>, rx: Receiver<RouteRequest>) {
+        while let Ok(request) = rx.recv() {
+            let response = match request {
+                RouteRequest::Waypoints {
+                    route_tokens,
+                    flight_plan,
+                } => RouteResponse::Waypoints(self.get_waypoints(&route_tokens, &flight_plan)),
+                RouteRequest::Fix(ident) => {
+                    RouteResponse::Fix(self.get_fix_candidates(ident).map(|candidates| FixLookup {
+                        db_versions: self.dbs.iter().map(|db| db.db_version).collect(),
+                        candidates,
+                    }))
+                }
+                RouteRequest::Airports(idents) => RouteResponse::Airports(
+                    idents
+                        .into_iter()
+                        .map(|ident| self.get_airport(ident))
+                        .collect(),
+                ),
+            };
+            if let Err(err) = tx.send(response) {
+                error!("Failed to send nav db response: {err}");
             }
         }
     }
 
-    fn map_table(&self, table: &'static str) -> &'static str {
-        if self.db_version == 1 {
-            return table;
-        }
-
-        match table {
-            "tbl_enroute_airways" => "tbl_er_enroute_airways",
-            "tbl_airports" => "tbl_pa_airports",
-            "tbl_enroute_waypoints" => "tbl_ea_enroute_waypoints",
-            "tbl_terminal_waypoints" => "tbl_pc_terminal_waypoints",
-            "tbl_vhfnavaids" => "tbl_d_vhfnavaids",
-            "tbl_enroute_ndbnavaids" => "tbl_db_enroute_ndbnavaids",
-            "tbl_terminal_ndbnavaids" => "tbl_pn_terminal_ndbnavaids",
-            "tbl_sids" => "tbl_pd_sids",
-            "tbl_stars" => "tbl_pe_stars",
-            _ => unreachable!(),
-        }
-    }
-
-    fn map_navaid_table_column(&self, table: &'static str, column: &'static str) -> String {
-        if self.db_version == 1 {
-            return column.to_owned();
-        }
-
-        assert!(table.contains("vhf") | table.contains("ndb"));
-        column.replace("vor", "navaid").replace("ndb", "navaid")
-    }
-
     fn get_waypoints(
         &self,
         route_tokens: &[String],
         flight_plan: &FlightPlan,
     ) -> Result<Vec<Waypoint>> {
         let mut wps: Vec<Waypoint> = Vec::new();
+        // Picks between same-named fixes until the route has a waypoint of its own
+        let origin = self.get_airport(flight_plan.departure.clone());
+        // `DEP DCT ARR` plans fly straight between the airports
+        if route_tokens.is_empty() {
+            return Ok(origin
+                .into_iter()
+                .chain(self.get_airport(flight_plan.arrival.clone()))
+                .collect());
+        }
+        let origin = origin.as_ref();
 
         let first = route_tokens[0].clone();
         let sid_pts = self
-            .fetch_procedure(flight_plan.departure.clone(), first.clone(), 'D')
+            .fetch_procedure(
+                flight_plan.departure.clone(),
+                first.clone(),
+                'D',
+                enroute_fix(route_tokens.iter().skip(1)),
+            )
             .unwrap_or_default();
         if !sid_pts.is_empty() {
             wps.extend(sid_pts);
         } else {
-            self.expand_token(&mut wps, &first, "")?;
+            self.expand_token(&mut wps, &first, "", origin, false)?;
         }
 
         for i in 1..route_tokens.len() - 1 {
-            self.expand_token(&mut wps, &route_tokens[i], &route_tokens[i + 1])?;
+            if route_tokens[i] == "DCT" {
+                continue;
+            }
+            let direct = route_tokens[i - 1] == "DCT";
+            self.expand_token(
+                &mut wps,
+                &route_tokens[i],
+                &route_tokens[i + 1],
+                origin,
+                direct,
+            )?;
         }
 
         let last = route_tokens.last().unwrap();
         let star_pts = self
-            .fetch_procedure(flight_plan.arrival.clone(), last.clone(), 'A')
+            .fetch_procedure(
+                flight_plan.arrival.clone(),
+                last.clone(),
+                'A',
+                enroute_fix(route_tokens.iter().rev().skip(1)),
+            )
             .unwrap_or_default();
         if !star_pts.is_empty() {
             wps.extend(star_pts);
         } else {
-            self.expand_token(&mut wps, last, "")?;
+            let direct = route_tokens.len() > 1 && route_tokens[route_tokens.len() - 2] == "DCT";
+            self.expand_token(&mut wps, last, "", origin, direct)?;
         }
 
         if let Some(wpt) = self.get_airport(flight_plan.arrival.clone()) {
@@ -450,19 +1429,30 @@ impl InnerRoute {
         Ok(result)
     }
 
-    fn expand_token(&self, wps: &mut Vec<Waypoint>, tok: &str, next_tok: &str) -> Result<()> {
+    fn expand_token(
+        &self,
+        wps: &mut Vec<Waypoint>,
+        tok: &str,
+        next_tok: &str,
+        origin: Option<&Waypoint>,
+        direct: bool,
+    ) -> Result<()> {
         let base = tok.split('/').next().unwrap();
-        let ll_re = Regex::new(r"^(\d{2})([NS])(\d{3})([EW])$").unwrap();
-        if let Some(c) = ll_re.captures(base) {
-            let dlat: f64 = c[1].parse().unwrap();
-            let dlon: f64 = c[3].parse().unwrap();
-            let lat = if &c[2] == "N" { dlat } else { -dlat };
-            let lon = if &c[4] == "E" { dlon } else { -dlon };
-            wps.push(Waypoint::new(base.to_string(), lat, lon));
-            return Ok(());
+        // `5030N/02000W` carries the longitude after the slash
+        for ident in [tok, base] {
+            if let Some((lat, lon)) = parse_coordinate(ident) {
+                wps.push(Waypoint::new(ident.to_string(), lat, lon));
+                return Ok(());
+            }
         }
 
         if base.starts_with("NAT") && base.len() == 4 {
+            if NAT_LOOKUP_DISABLED.load(Ordering::Relaxed) {
+                warn!(
+                    "NAT lookup disabled, {base} only follows the coordinates filed in the route"
+                );
+                return Ok(());
+            }
             if let Ok(pts) = self.fetch_nattrak(&base[3..4]) {
                 for wpt in pts {
                     if !wpt.lat.is_nan() {
@@ -477,7 +1467,7 @@ impl InnerRoute {
         }
         let fixes = self.get_fix(base.to_owned())?;
         if !fixes.is_empty() {
-            if let Some(prev) = wps.last() {
+            if let Some(prev) = wps.last().or(origin) {
                 let mut min_dist = f64::INFINITY;
                 let mut best = None;
                 for cand in fixes {
@@ -499,7 +1489,8 @@ impl InnerRoute {
             return Ok(());
         }
 
-        if !wps.is_empty() {
+        // After a DCT the token is a fix to fly to, never an airway to join
+        if !direct && !wps.is_empty() {
             let join_fix = wps.last().map(|wpt| wpt.id.clone()).unwrap();
             let exit_fix = next_tok.split('/').next().unwrap().to_string();
             let awy_pts = self.fetch_airway(base.to_owned(), join_fix, exit_fix)?;
@@ -508,6 +1499,196 @@ impl InnerRoute {
         Ok(())
     }
 
+    fn fetch_airway(
+        &self,
+        awy: String,
+        join_fix: String,
+        exit_fix: String,
+    ) -> Result<Vec<Waypoint>> {
+        let key = (awy, join_fix, exit_fix);
+        if let Some(pts) = self.airway_cache.borrow().get(&key) {
+            return Ok(pts.clone());
+        }
+
+        let mut pts = Vec::new();
+        for db in &self.dbs {
+            pts = db.fetch_airway(key.0.clone(), key.1.clone(), key.2.clone())?;
+            if !pts.is_empty() {
+                break;
+            }
+        }
+        self.airway_cache.borrow_mut().insert(key, pts.clone());
+        Ok(pts)
+    }
+
+    fn get_airport(&self, ident: String) -> Option<Waypoint> {
+        self.dbs.iter().find_map(|db| db.get_airport(ident.clone()))
+    }
+
+    fn get_fix(&self, ident: String) -> Result<Vec<Waypoint>> {
+        Ok(self
+            .get_fix_candidates(ident)?
+            .into_iter()
+            .map(|c| c.waypoint)
+            .collect())
+    }
+
+    fn get_fix_candidates(&self, ident: String) -> Result<Vec<FixCandidate>> {
+        if let Some(candidates) = self.fix_cache.borrow().get(&ident) {
+            return Ok(candidates.clone());
+        }
+
+        let mut candidates = Vec::new();
+        for db in &self.dbs {
+            candidates.extend(db.get_fix_candidates(ident.clone())?);
+        }
+        self.fix_cache
+            .borrow_mut()
+            .insert(ident, candidates.clone());
+        Ok(candidates)
+    }
+
+    fn fetch_procedure(
+        &self,
+        airport: String,
+        proc_token: String,
+        kind: char,
+        enroute_fix: &str,
+    ) -> Result<Vec<Waypoint>> {
+        for db in &self.dbs {
+            let pts = db.fetch_procedure(airport.clone(), proc_token.clone(), kind, enroute_fix)?;
+            if !pts.is_empty() {
+                return Ok(pts);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn fetch_nattrak(&self, track_id: &str) -> Result<Vec<Waypoint>, String> {
+        let response = reqwest::blocking::get("https://nattrak.vatsim.net/api/tracks")
+            .map_err(|e| e.to_string())?;
+
+        #[derive(Debug, Clone, Deserialize)]
+        struct NatTrack {
+            identifier: String,
+            active: bool,
+            last_routeing: String,
+        }
+
+        let tracks: Vec<NatTrack> = response.json().map_err(|e| e.to_string())?;
+
+        let track = tracks
+            .iter()
+            .find(|t| t.identifier.eq_ignore_ascii_case(track_id) && t.active)
+            .ok_or_else(|| format!("Track {track_id} not found or not active"))?;
+
+        let coord_re = Regex::new(r"^(\d{2}(?:\d{2})?)/(\d{2}(?:\d{2})?)$").unwrap();
+        let mut pts = Vec::new();
+
+        for tok in track.last_routeing.split_whitespace() {
+            if let Some(caps) = coord_re.captures(tok) {
+                let lat = parse_latlon_token(&caps[1]);
+                let lon = parse_latlon_token(&caps[2]);
+                pts.push(Waypoint::new(tok.to_string(), lat, -lon));
+            }
+        }
+        Ok(pts)
+    }
+}
+
+/// `current_airac` from a navigraph header table, stored as text or a number depending on the build.
+fn read_airac_cycle(conn: &Connection, table: &str) -> Option<String> {
+    let value = conn
+        .query_row(&format!("SELECT current_airac FROM {table}"), [], |row| {
+            row.get::<_, rusqlite::types::Value>(0)
+        })
+        .ok()?;
+    match value {
+        rusqlite::types::Value::Text(cycle) if !cycle.trim().is_empty() => {
+            Some(cycle.trim().to_owned())
+        }
+        rusqlite::types::Value::Integer(cycle) => Some(cycle.to_string()),
+        _ => None,
+    }
+}
+
+/// Checks `path` is an existing navigraph SQLite database, without creating it like `Connection::open` would.
+pub fn check_nav_db(path: &str) -> Result<()> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("Nav db {path} does not exist"))?;
+    if metadata.is_dir() {
+        bail!("Nav db {path} is a directory, pass the path of the database file");
+    }
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Could not open nav db {path}"))?;
+    let db = NavDb::new(conn).with_context(|| format!("{path} is not a SQLite database"))?;
+    if !db.tables.iter().any(|table| table.starts_with("tbl_")) {
+        bail!("{path} does not contain any navigation data tables");
+    }
+    Ok(())
+}
+
+struct NavDb {
+    conn: Connection,
+    db_version: usize,
+    tables: HashSet<String>,
+    cycle: Option<String>,
+}
+
+impl NavDb {
+    fn new(conn: Connection) -> Result<NavDb> {
+        let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table'")?;
+        let tables = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()?;
+        let db_version = if tables.contains("tbl_header") { 1 } else { 2 };
+        drop(stmt);
+        let cycle = ["tbl_header", "tbl_hdr_header"]
+            .into_iter()
+            .filter(|table| tables.contains(*table))
+            .find_map(|table| read_airac_cycle(&conn, table));
+        debug!("Database version: {db_version}, AIRAC cycle: {cycle:?}");
+        Ok(NavDb {
+            conn,
+            db_version,
+            tables,
+            cycle,
+        })
+    }
+
+    fn has_table(&self, table: &str) -> bool {
+        self.tables.contains(table)
+    }
+
+    fn map_table(&self, table: &'static str) -> &'static str {
+        if self.db_version == 1 {
+            return table;
+        }
+
+        match table {
+            "tbl_enroute_airways" => "tbl_er_enroute_airways",
+            "tbl_airports" => "tbl_pa_airports",
+            "tbl_enroute_waypoints" => "tbl_ea_enroute_waypoints",
+            "tbl_terminal_waypoints" => "tbl_pc_terminal_waypoints",
+            "tbl_vhfnavaids" => "tbl_d_vhfnavaids",
+            "tbl_enroute_ndbnavaids" => "tbl_db_enroute_ndbnavaids",
+            "tbl_terminal_ndbnavaids" => "tbl_pn_terminal_ndbnavaids",
+            "tbl_sids" => "tbl_pd_sids",
+            "tbl_stars" => "tbl_pe_stars",
+            _ => unreachable!(),
+        }
+    }
+
+    fn map_navaid_table_column(&self, table: &'static str, column: &'static str) -> String {
+        if self.db_version == 1 {
+            return column.to_owned();
+        }
+
+        assert!(table.contains("vhf") | table.contains("ndb"));
+        column.replace("vor", "navaid").replace("ndb", "navaid")
+    }
+
     fn fetch_airway(
         &self,
         awy: String,
@@ -515,8 +1696,12 @@ impl InnerRoute {
         exit_fix: String,
     ) -> Result<Vec<Waypoint>> {
         let mut out = Vec::new();
-        let query = format!("SELECT waypoint_identifier, waypoint_latitude, waypoint_longitude FROM {} WHERE route_identifier = ? ORDER BY seqno DESC",  self.map_table("tbl_enroute_airways"));
-        let mut stmt = self.conn.prepare(&query)?;
+        let table = self.map_table("tbl_enroute_airways");
+        if !self.has_table(table) {
+            return Ok(out);
+        }
+        let query = format!("SELECT waypoint_identifier, waypoint_latitude, waypoint_longitude FROM {} WHERE route_identifier = ? ORDER BY seqno DESC",  table);
+        let mut stmt = self.conn.prepare_cached(&query)?;
         let mut rows = stmt.query([awy.clone()])?;
         while let Ok(Some(r)) = rows.next() {
             out.push(Waypoint::new(r.get(0)?, r.get(1)?, r.get(2)?));
@@ -534,30 +1719,38 @@ impl InnerRoute {
             Some(i) => i,
             None => return Ok(Vec::new()),
         };
-        if start + 1 == end {
+        // Only the fixes strictly between the two endpoints, in the direction flown
+        let (lo, hi) = (start.min(end), start.max(end));
+        if hi - lo <= 1 {
             return Ok(Vec::new());
         }
-        if start + 1 > end {
-            let mut res = out[end + 1..start].to_vec();
+        let mut res = out[lo + 1..hi].to_vec();
+        if start > end {
             res.reverse();
-            return Ok(res);
         }
-        Ok(out[(start + 1)..end].to_vec())
+        Ok(res)
     }
 
     fn get_airport(&self, ident: String) -> Option<Waypoint> {
-        let query = format!("SELECT airport_ref_latitude, airport_ref_longitude FROM {} WHERE airport_identifier = ?", self.map_table("tbl_airports"));
-        let mut stmt = self.conn.prepare(&query).unwrap();
+        let table = self.map_table("tbl_airports");
+        if !self.has_table(table) {
+            return None;
+        }
+        let query = format!("SELECT airport_ref_latitude, airport_ref_longitude FROM {table} WHERE airport_identifier = ?");
+        let mut stmt = self.conn.prepare_cached(&query).unwrap();
         stmt.query_row([ident.clone()], |r| Ok((r.get(0)?, r.get(1)?)))
             .map(|row| Waypoint::new(ident, row.0, row.1))
             .ok()
     }
 
-    fn get_fix(&self, ident: String) -> Result<Vec<Waypoint>> {
+    fn get_fix_candidates(&self, ident: String) -> Result<Vec<FixCandidate>> {
         let mut candidates = Vec::new();
 
-        let mut try_stmt = |sql: &str| -> rusqlite::Result<_> {
-            let mut stmt = self.conn.prepare(sql)?;
+        let mut try_stmt = |source: &'static str, sql: &str| -> rusqlite::Result<_> {
+            if !self.has_table(source) {
+                return Ok(());
+            }
+            let mut stmt = self.conn.prepare_cached(sql)?;
             let mut rows = stmt.query([&ident])?;
             while let Ok(Some(row)) = rows.next() {
                 let lat: Option<f64> = row.get(0)?;
@@ -565,40 +1758,55 @@ impl InnerRoute {
                     Some(lat) => (lat, row.get(1)?),
                     None => (row.get(2)?, row.get(3)?),
                 };
-                candidates.push(Waypoint::new(ident.clone(), lat, lon));
+                candidates.push(FixCandidate {
+                    waypoint: Waypoint::new(ident.clone(), lat, lon),
+                    source,
+                    db_version: self.db_version,
+                });
             }
             Ok(())
         };
 
-        try_stmt(&format!(
-            "SELECT waypoint_latitude, waypoint_longitude FROM {} WHERE waypoint_identifier = ?",
-            self.map_table("tbl_enroute_waypoints")
-        ))?;
-        try_stmt(&format!(
-            "SELECT waypoint_latitude, waypoint_longitude FROM {} WHERE waypoint_identifier = ?",
-            self.map_table("tbl_terminal_waypoints")
-        ))?;
-        try_stmt(&format!(
-            "SELECT dme_latitude, dme_longitude, {}, {} FROM {} WHERE {} = ?",
-            self.map_navaid_table_column("tbl_vhfnavaids", "vor_latitude"),
-            self.map_navaid_table_column("tbl_vhfnavaids", "vor_longitude"),
-            self.map_table("tbl_vhfnavaids"),
-            self.map_navaid_table_column("tbl_vhfnavaids", "vor_identifier")
-        ))?;
-        try_stmt(&format!(
-            "SELECT {}, {} FROM {} WHERE {} = ?",
-            self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_latitude"),
-            self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_longitude"),
-            self.map_table("tbl_enroute_ndbnavaids"),
-            self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_identifier")
-        ))?;
-        try_stmt(&format!(
-            "SELECT {}, {} FROM {} WHERE {} = ?",
-            self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_latitude"),
-            self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_longitude"),
-            self.map_table("tbl_terminal_ndbnavaids"),
-            self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_identifier")
-        ))?;
+        let table = self.map_table("tbl_enroute_waypoints");
+        try_stmt(
+            table,
+            &format!("SELECT waypoint_latitude, waypoint_longitude FROM {table} WHERE waypoint_identifier = ?"),
+        )?;
+        let table = self.map_table("tbl_terminal_waypoints");
+        try_stmt(
+            table,
+            &format!("SELECT waypoint_latitude, waypoint_longitude FROM {table} WHERE waypoint_identifier = ?"),
+        )?;
+        let table = self.map_table("tbl_vhfnavaids");
+        try_stmt(
+            table,
+            &format!(
+                "SELECT dme_latitude, dme_longitude, {}, {} FROM {table} WHERE {} = ?",
+                self.map_navaid_table_column("tbl_vhfnavaids", "vor_latitude"),
+                self.map_navaid_table_column("tbl_vhfnavaids", "vor_longitude"),
+                self.map_navaid_table_column("tbl_vhfnavaids", "vor_identifier")
+            ),
+        )?;
+        let table = self.map_table("tbl_enroute_ndbnavaids");
+        try_stmt(
+            table,
+            &format!(
+                "SELECT {}, {} FROM {table} WHERE {} = ?",
+                self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_latitude"),
+                self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_longitude"),
+                self.map_navaid_table_column("tbl_enroute_ndbnavaids", "ndb_identifier")
+            ),
+        )?;
+        let table = self.map_table("tbl_terminal_ndbnavaids");
+        try_stmt(
+            table,
+            &format!(
+                "SELECT {}, {} FROM {table} WHERE {} = ?",
+                self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_latitude"),
+                self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_longitude"),
+                self.map_navaid_table_column("tbl_terminal_ndbnavaids", "ndb_identifier")
+            ),
+        )?;
 
         Ok(candidates)
     }
@@ -608,14 +1816,24 @@ impl InnerRoute {
         airport: String,
         proc_token: String,
         kind: char,
+        enroute_fix: &str,
     ) -> Result<Vec<Waypoint>> {
         let table = match kind {
             'D' => self.map_table("tbl_sids"),
             'A' => self.map_table("tbl_stars"),
             _ => unreachable!(),
         };
+        if !self.has_table(table) {
+            return Ok(Vec::new());
+        }
 
         let raw = proc_token.split('/').next().unwrap();
+        let runway = proc_token.split('/').nth(1).and_then(|rwy| {
+            Regex::new(r"^(?:RW)?(\d{2})([LRC]?)$")
+                .unwrap()
+                .captures(rwy)
+                .map(|c| (format!("RW{}{}", &c[1], &c[2]), format!("RW{}B", &c[1])))
+        });
         let re = Regex::new(r"^([A-Z]+?)(\d.*)?$").unwrap();
         let (wp_pref, num_suf) = re
             .captures(raw)
@@ -628,7 +1846,7 @@ impl InnerRoute {
             .unwrap_or((raw, ""));
 
         let sql = format!("SELECT DISTINCT procedure_identifier, transition_identifier FROM {table} WHERE airport_identifier = ?");
-        let mut stmt = self.conn.prepare(&sql).unwrap();
+        let mut stmt = self.conn.prepare_cached(&sql).unwrap();
         let mut rows = stmt.query([&airport]).unwrap();
 
         let mut candidates = Vec::new();
@@ -650,7 +1868,8 @@ impl InnerRoute {
             let score = (raw.eq_ignore_ascii_case(&full_key) as usize) * 100
                 + (proc_token.eq_ignore_ascii_case(&full_key) as usize) * 50
                 + (wp_pref.eq_ignore_ascii_case(&full_key[..wp_pref.len()]) as usize) * 10
-                + ((!num_suf.is_empty() && full_key.ends_with(num_suf)) as usize) * 5;
+                + ((!num_suf.is_empty() && full_key.ends_with(num_suf)) as usize) * 5
+                + runway_score(&runway, trans_id.as_deref());
 
             if score > best_score {
                 best_score = score;
@@ -671,59 +1890,124 @@ impl InnerRoute {
             Some(x) if best_score > 0 => x,
             _ => return Ok(Vec::new()),
         };
-        let mut proc_rows = Vec::new();
-        let sql = format!("SELECT waypoint_identifier, waypoint_latitude, waypoint_longitude FROM {table} WHERE airport_identifier = ? AND procedure_identifier = ? AND (transition_identifier = ? OR transition_identifier IS NULL) AND waypoint_latitude IS NOT NULL ORDER BY seqno {}", if kind == 'D' { "DESC" } else { "" });
-        let mut stmt = self.conn.prepare(&sql)?;
-        let mut rows = stmt.query([&airport, &proc_id, trans_id.as_deref().unwrap_or("")])?;
-        while let Ok(Some(row)) = rows.next() {
-            let id: String = row.get(0)?;
-            let lat: f64 = row.get(1)?;
-            let lon: f64 = row.get(2)?;
-            proc_rows.push(Waypoint::new(id, lat, lon));
-        }
-        Ok(proc_rows)
-    }
 
-    fn fetch_nattrak(&self, track_id: &str) -> Result<Vec<Waypoint>, String> {
-        let response = reqwest::blocking::get("https://nattrak.vatsim.net/api/tracks")
-            .map_err(|e| e.to_string())?;
+        // The runway and the enroute transitions are stored separately from the common route,
+        // each numbered from the start, so they are fetched one by one and joined in flying order
+        let transitions = candidates
+            .iter()
+            .filter(|(id, _)| *id == proc_id)
+            .filter_map(|(_, t)| t.as_deref())
+            .collect::<Vec<_>>();
+        let is_runway = |t: &str| t.starts_with("RW");
+        let runway_trans = match trans_id.as_deref() {
+            Some(t) if is_runway(t) => Some(t),
+            _ => transitions
+                .iter()
+                .copied()
+                .filter(|t| runway_score(&runway, Some(t)) > 0)
+                .max_by_key(|t| runway_score(&runway, Some(t))),
+        };
+        let enroute_trans = match trans_id.as_deref() {
+            Some(t) if !is_runway(t) => Some(t),
+            _ => transitions
+                .iter()
+                .copied()
+                .find(|t| !is_runway(t) && t.eq_ignore_ascii_case(enroute_fix)),
+        };
+        let legs = match kind {
+            'D' => [runway_trans, None, enroute_trans],
+            _ => [enroute_trans, None, runway_trans],
+        };
 
-        #[derive(Debug, Clone, Deserialize)]
-        struct NatTrack {
-            identifier: String,
-            active: bool,
-            last_routeing: String,
+        let mut proc_rows: Vec<Waypoint> = Vec::new();
+        let sql = format!("SELECT waypoint_identifier, waypoint_latitude, waypoint_longitude FROM {table} WHERE airport_identifier = ? AND procedure_identifier = ? AND transition_identifier IS ? AND waypoint_latitude IS NOT NULL ORDER BY seqno");
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        for (i, trans) in legs.into_iter().enumerate() {
+            // Only the common route is stored without a transition
+            if trans.is_none() && i != 1 {
+                continue;
+            }
+            let mut rows = stmt.query(rusqlite::params![&airport, &proc_id, trans])?;
+            while let Ok(Some(row)) = rows.next() {
+                let id: String = row.get(0)?;
+                let lat: f64 = row.get(1)?;
+                let lon: f64 = row.get(2)?;
+                // Transitions start or end at the fix they share with the common route
+                if proc_rows.last().is_some_and(|last| last.id == id) {
+                    continue;
+                }
+                proc_rows.push(Waypoint::new(id, lat, lon));
+            }
         }
+        Ok(proc_rows)
+    }
+}
 
-        let tracks: Vec<NatTrack> = response.json().map_err(|e| e.to_string())?;
+// Speed and level groups like `N0450F350` or `M084F390`, and flight rule changes, that are not fixes.
+// Bare `A461` or `M300` are airways, so only full groups are dropped
+static SPEED_LEVEL_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:(?:[NK]\d{4}|M\d{3})(?:[FA]\d{3}|[SM]\d{4})|VFR|IFR)$").unwrap()
+});
+
+/// Splits a flight plan route, keeping `DCT` between fixes but not at either end.
+fn route_tokens(route: &str) -> Vec<String> {
+    let mut tokens = route
+        .split_whitespace()
+        .map(|x| x.to_uppercase())
+        .filter(|x| !SPEED_LEVEL_RE.is_match(x))
+        .collect::<Vec<_>>();
+    while tokens.first().is_some_and(|t| t == "DCT") {
+        tokens.remove(0);
+    }
+    while tokens.last().is_some_and(|t| t == "DCT") {
+        tokens.pop();
+    }
+    tokens.dedup_by(|a, b| a == "DCT" && b == "DCT");
+    tokens
+}
 
-        let track = tracks
-            .iter()
-            .find(|t| t.identifier.eq_ignore_ascii_case(track_id) && t.active)
-            .ok_or_else(|| format!("Track {track_id} not found or not active"))?;
+/// The enroute fix a SID leads to or a STAR starts from, the first of `tokens` that is not `DCT`.
+fn enroute_fix<'a>(mut tokens: impl Iterator<Item = &'a String>) -> &'a str {
+    tokens
+        .find(|tok| *tok != "DCT")
+        .and_then(|tok| tok.split('/').next())
+        .unwrap_or_default()
+}
 
-        let coord_re = Regex::new(r"^(\d{2}(?:\d{2})?)/(\d{2}(?:\d{2})?)$").unwrap();
-        let mut pts = Vec::new();
+/// Prefers the transition for the filed runway, or one serving both parallel runways.
+fn runway_score(runway: &Option<(String, String)>, trans_id: Option<&str>) -> usize {
+    match (runway, trans_id) {
+        (Some((exact, _)), Some(t)) if exact.eq_ignore_ascii_case(t) => 200,
+        (Some((_, both)), Some(t)) if both.eq_ignore_ascii_case(t) => 150,
+        _ => 0,
+    }
+}
 
-        for tok in track.last_routeing.split_whitespace() {
-            if let Some(caps) = coord_re.captures(tok) {
-                let lat = parse_latlon_token(&caps[1]);
-                let lon = parse_latlon_token(&caps[2]);
-                pts.push(Waypoint::new(tok.to_string(), lat, -lon));
-            }
-        }
-        Ok(pts)
+/// Which side of the `from` -> `to` segment `pos` is on.
+fn cross_track_side(from: &Waypoint, to: &Waypoint, pos: Point) -> &'static str {
+    let from = Point::new(from.lon, from.lat);
+    let course = Haversine.bearing(from, Point::new(to.lon, to.lat));
+    let to_pos = Haversine.bearing(from, pos);
+    if (to_pos - course).rem_euclid(360.0) < 180.0 {
+        "right"
+    } else {
+        "left"
     }
 }
 
+/// A segment before the last known one is only chosen when it is closer by more than this.
+const BACKWARD_JUMP_M: f64 = 10.0 * 1852.0;
+
 pub fn find_closest_segment(
     waypoints: &[Waypoint],
     lat: f64,
     lon: f64,
+    last_idx: Option<usize>,
 ) -> Option<(usize, usize, Waypoint, Waypoint, f64)> {
     let p = Point::new(lon, lat);
-    let mut best: Option<(usize, usize, Waypoint, Waypoint, f64)> = None;
-    let mut best_dev_nm = f64::INFINITY;
+    let min_idx = last_idx.unwrap_or(0);
+    let mut best_forward: Option<(usize, f64)> = None;
+    let mut best_backward: Option<(usize, f64)> = None;
 
     for i in 0..waypoints.len().saturating_sub(1) {
         let a = &waypoints[i];
@@ -736,14 +2020,42 @@ pub fn find_closest_segment(
             line.haversine_closest_point(&p)
         {
             let dev_m = Haversine.distance(p, proj);
-            if dev_m < best_dev_nm {
-                best_dev_nm = dev_m;
-                best = Some((i, i + 1, a.clone(), b.clone(), dev_m));
+            let best = if i >= min_idx {
+                &mut best_forward
+            } else {
+                &mut best_backward
+            };
+            if best.is_none_or(|(_, best_dev_m)| dev_m < best_dev_m) {
+                *best = Some((i, dev_m));
             }
         }
     }
 
-    best
+    let (i, dev_m) = match (best_forward, best_backward) {
+        (Some(forward), Some(backward)) if forward.1 - backward.1 > BACKWARD_JUMP_M => backward,
+        (Some(forward), _) => forward,
+        (None, backward) => backward?,
+    };
+    Some((
+        i,
+        i + 1,
+        waypoints[i].clone(),
+        waypoints[i + 1].clone(),
+        dev_m,
+    ))
+}
+
+/// Summarises a route change as `+added -removed` tokens.
+fn route_diff(previous: &[String], current: &[String]) -> String {
+    let added = current
+        .iter()
+        .filter(|tok| !previous.contains(tok))
+        .map(|tok| format!("+{tok}"));
+    let removed = previous
+        .iter()
+        .filter(|tok| !current.contains(tok))
+        .map(|tok| format!("-{tok}"));
+    added.chain(removed).collect::<Vec<_>>().join(" ")
 }
 
 fn route_length_nm(waypoints: &[Waypoint]) -> f64 {
@@ -758,21 +2070,39 @@ fn route_length_nm(waypoints: &[Waypoint]) -> f64 {
         / 1852.0
 }
 
-fn has_loop(wps: &[Waypoint]) -> bool {
-    let pts: Vec<Point<f64>> = wps.iter().map(|wp| Point::new(wp.lon, wp.lat)).collect();
+fn has_loop(wps: &[Waypoint], thresholds: &CrashThresholds, airports: &[&Waypoint]) -> bool {
+    let distance_nm = |a: &Waypoint, b: &Waypoint| {
+        Haversine.distance(Point::new(a.lon, a.lat), Point::new(b.lon, b.lat)) / 1852.0
+    };
+    let near_airport = |wp: &Waypoint| {
+        airports
+            .iter()
+            .any(|apt| distance_nm(apt, wp) < thresholds.loop_airport_radius_nm)
+    };
 
-    for i in 0..pts.len().saturating_sub(1) {
-        let a1 = pts[i];
-        let a2 = pts[i + 1];
-        let seg1 = Line::new(a1.0, a2.0);
-        for j in (i + 2)..pts.len().saturating_sub(1) {
-            if j == i + 1 {
-                continue;
-            }
-            let b1 = pts[j];
-            let b2 = pts[j + 1];
-            let seg2 = Line::new(b1.0, b2.0);
-            if seg1.intersects(&seg2) {
+    let segments: Vec<(usize, Line<f64>)> = wps
+        .windows(2)
+        .enumerate()
+        .filter(|(_, w)| {
+            distance_nm(&w[0], &w[1]) >= thresholds.loop_min_segment_nm
+                && !near_airport(&w[0])
+                && !near_airport(&w[1])
+        })
+        .map(|(i, w)| {
+            (
+                i,
+                Line::new(
+                    Point::new(w[0].lon, w[0].lat).0,
+                    Point::new(w[1].lon, w[1].lat).0,
+                ),
+            )
+        })
+        .collect();
+
+    let min_gap = thresholds.loop_min_index_gap.max(2);
+    for (n, (i, seg1)) in segments.iter().enumerate() {
+        for (j, seg2) in &segments[n + 1..] {
+            if j - i >= min_gap && seg1.intersects(seg2) {
                 return true;
             }
         }
@@ -791,3 +2121,694 @@ fn parse_latlon_token(token: &str) -> f64 {
         _ => f64::NAN,
     }
 }
+
+static COORD_SUFFIX_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d{2})(\d{2})?([NS])/?(\d{3})(\d{2})?([EW])$").unwrap());
+static COORD_PREFIX_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([NS])(\d{2})(\d{2})?([EW])(\d{3})(\d{2})?$").unwrap());
+// ARINC 424 five character oceanic points, eg. 5030N, N5030 and H5030
+static COORD_ARINC_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:(\d{2})(\d{2})([NESW])|([NESWHJKL])(\d{2})(\d{2}))$").unwrap()
+});
+
+fn degrees_minutes(deg: &str, min: Option<&str>) -> f64 {
+    let deg: f64 = deg.parse().unwrap();
+    let min: f64 = min.map_or(0.0, |m| m.parse().unwrap());
+    deg + min / 60.0
+}
+
+/// Decodes a lat/lon route token, eg. `50N020W`, `5030N02000W` or `N5030W02000`.
+fn parse_coordinate(token: &str) -> Option<(f64, f64)> {
+    let signed = |value: f64, hemisphere: &str, positive: &str| {
+        if hemisphere == positive {
+            value
+        } else {
+            -value
+        }
+    };
+
+    if let Some(c) = COORD_SUFFIX_RE.captures(token) {
+        let lat = degrees_minutes(&c[1], c.get(2).map(|m| m.as_str()));
+        let lon = degrees_minutes(&c[4], c.get(5).map(|m| m.as_str()));
+        return Some((signed(lat, &c[3], "N"), signed(lon, &c[6], "E")));
+    }
+
+    if let Some(c) = COORD_PREFIX_RE.captures(token) {
+        let lat = degrees_minutes(&c[2], c.get(3).map(|m| m.as_str()));
+        let lon = degrees_minutes(&c[5], c.get(6).map(|m| m.as_str()));
+        return Some((signed(lat, &c[1], "N"), signed(lon, &c[4], "E")));
+    }
+
+    let c = COORD_ARINC_RE.captures(token)?;
+    let (lat, lon, quadrant) = match c.get(3) {
+        // Letter last: longitude below 100
+        Some(q) => (
+            degrees_minutes(&c[1], None),
+            degrees_minutes(&c[2], None),
+            q.as_str(),
+        ),
+        // Letter first: longitude 100 and above, or half a degree more latitude
+        None => {
+            let half = matches!(&c[4], "H" | "J" | "K" | "L");
+            let lat = degrees_minutes(&c[5], None) + if half { 0.5 } else { 0.0 };
+            let lon = degrees_minutes(&c[6], None) + if half { 0.0 } else { 100.0 };
+            (lat, lon, c.get(4).unwrap().as_str())
+        }
+    };
+    Some(match quadrant {
+        "N" | "H" => (lat, -lon),
+        "E" | "J" => (lat, lon),
+        "S" | "K" => (-lat, lon),
+        _ => (-lat, -lon),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waypoint_db(waypoints: &[(&str, f64, f64)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE tbl_ea_enroute_waypoints (waypoint_identifier TEXT, waypoint_latitude REAL, waypoint_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        for (id, lat, lon) in waypoints {
+            conn.execute(
+                "INSERT INTO tbl_ea_enroute_waypoints VALUES (?, ?, ?)",
+                rusqlite::params![id, lat, lon],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn airway_db(airway: &str, fixes: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        add_airway(&conn, airway, fixes);
+        conn
+    }
+
+    fn add_airway(conn: &Connection, airway: &str, fixes: &[&str]) {
+        conn.execute(
+            "CREATE TABLE tbl_er_enroute_airways (route_identifier TEXT, seqno INTEGER, waypoint_identifier TEXT, waypoint_latitude REAL, waypoint_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        for (i, id) in fixes.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO tbl_er_enroute_airways VALUES (?, ?, ?, ?, ?)",
+                rusqlite::params![airway, (i as i64 + 1) * 10, id, 50.0, i as f64],
+            )
+            .unwrap();
+        }
+    }
+
+    fn route_ids(ir: &InnerRoute, route: &str) -> Vec<String> {
+        ir.get_waypoints(&route_tokens(route), &FlightPlan::default())
+            .unwrap()
+            .into_iter()
+            .map(|wpt| wpt.id)
+            .collect()
+    }
+
+    fn add_airports(conn: &Connection, airports: &[(&str, f64, f64)]) {
+        conn.execute(
+            "CREATE TABLE tbl_pa_airports (airport_identifier TEXT, airport_ref_latitude REAL, airport_ref_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        for (id, lat, lon) in airports {
+            conn.execute(
+                "INSERT INTO tbl_pa_airports VALUES (?, ?, ?)",
+                rusqlite::params![id, lat, lon],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn direct_and_single_fix_plans() {
+        let conn = waypoint_db(&[("BPK", 51.7, -0.1)]);
+        add_airports(&conn, &[("EGLL", 51.5, -0.5), ("EGKK", 51.1, -0.2)]);
+        let ir = InnerRoute::new(vec![conn]).unwrap();
+        let plan = FlightPlan {
+            departure: "EGLL".to_owned(),
+            arrival: "EGKK".to_owned(),
+            ..Default::default()
+        };
+        let ids = |route: &str| {
+            ir.get_waypoints(&route_tokens(route), &plan)
+                .unwrap()
+                .into_iter()
+                .map(|wpt| wpt.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids("DCT"), ["EGLL", "EGKK"]);
+        assert_eq!(ids(""), ["EGLL", "EGKK"]);
+        assert_eq!(ids("BPK"), ["BPK", "EGKK"]);
+    }
+
+    fn dct_db() -> InnerRoute {
+        let conn = waypoint_db(&[("FIXAA", 50.0, 0.0), ("FIXBB", 50.0, 3.0)]);
+        add_airway(&conn, "Q1", &["FIXAA", "MIDAA", "MIDBB", "FIXBB"]);
+        InnerRoute::new(vec![conn]).unwrap()
+    }
+
+    #[test]
+    fn direct_between_fixes() {
+        assert_eq!(route_ids(&dct_db(), "FIXAA DCT FIXBB"), ["FIXAA", "FIXBB"]);
+        // An airway name after DCT is not joined
+        assert_eq!(
+            route_ids(&dct_db(), "FIXAA DCT Q1 FIXBB"),
+            ["FIXAA", "FIXBB"]
+        );
+    }
+
+    #[test]
+    fn airway_between_fixes() {
+        assert_eq!(
+            route_ids(&dct_db(), "FIXAA Q1 FIXBB"),
+            ["FIXAA", "MIDAA", "MIDBB", "FIXBB"]
+        );
+    }
+
+    #[test]
+    fn speed_and_level_groups_dropped() {
+        assert_eq!(
+            route_tokens("N0450F350 FIXAA DCT FIXBB/N0460F370 Q1 FIXCC M084F390 K0830S1130 IFR VFR DCT FIXDD"),
+            ["FIXAA", "DCT", "FIXBB/N0460F370", "Q1", "FIXCC", "DCT", "FIXDD"]
+        );
+    }
+
+    #[test]
+    fn airways_like_speed_or_level_kept() {
+        assert_eq!(route_tokens("FIXAA A461 FIXBB"), ["FIXAA", "A461", "FIXBB"]);
+        assert_eq!(route_tokens("FIXAA M300 FIXBB"), ["FIXAA", "M300", "FIXBB"]);
+    }
+
+    #[test]
+    fn fixes_with_speed_level_suffix() {
+        let ir = InnerRoute::new(vec![waypoint_db(&[
+            ("FIXAA", 50.0, 0.0),
+            ("FIXBB", 50.0, 1.0),
+            ("FIXCC", 50.0, 2.0),
+        ])])
+        .unwrap();
+        let wps = ir
+            .get_waypoints(
+                &route_tokens("N0450F350 FIXAA/F370 FIXBB/N0460F390 M084F390 FIXCC/M082S1190"),
+                &FlightPlan::default(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|wpt| wpt.id)
+            .collect::<Vec<_>>();
+        assert_eq!(wps, ["FIXAA", "FIXBB", "FIXCC"]);
+    }
+
+    #[test]
+    fn dct_trimmed_from_route_ends() {
+        assert_eq!(
+            route_tokens("dct FIXAA DCT DCT FIXBB DCT"),
+            ["FIXAA", "DCT", "FIXBB"]
+        );
+    }
+
+    fn airway_ids(ir: &InnerRoute, join_fix: &str, exit_fix: &str) -> Vec<String> {
+        ir.fetch_airway("Q1".to_owned(), join_fix.to_owned(), exit_fix.to_owned())
+            .unwrap()
+            .into_iter()
+            .map(|wpt| wpt.id)
+            .collect()
+    }
+
+    fn q1() -> InnerRoute {
+        InnerRoute::new(vec![airway_db(
+            "Q1",
+            &["AAAAA", "BBBBB", "CCCCC", "DDDDD", "EEEEE"],
+        )])
+        .unwrap()
+    }
+
+    #[test]
+    fn airway_forward() {
+        assert_eq!(
+            airway_ids(&q1(), "AAAAA", "EEEEE"),
+            ["BBBBB", "CCCCC", "DDDDD"]
+        );
+        assert_eq!(airway_ids(&q1(), "BBBBB", "DDDDD"), ["CCCCC"]);
+    }
+
+    #[test]
+    fn airway_reverse() {
+        assert_eq!(
+            airway_ids(&q1(), "EEEEE", "AAAAA"),
+            ["DDDDD", "CCCCC", "BBBBB"]
+        );
+        assert_eq!(airway_ids(&q1(), "DDDDD", "BBBBB"), ["CCCCC"]);
+    }
+
+    #[test]
+    fn airway_adjacent_fixes() {
+        assert!(airway_ids(&q1(), "BBBBB", "CCCCC").is_empty());
+        assert!(airway_ids(&q1(), "CCCCC", "BBBBB").is_empty());
+        assert!(airway_ids(&q1(), "CCCCC", "CCCCC").is_empty());
+    }
+
+    #[test]
+    fn airway_missing_endpoint() {
+        assert!(airway_ids(&q1(), "ZZZZZ", "CCCCC").is_empty());
+        assert!(airway_ids(&q1(), "AAAAA", "ZZZZZ").is_empty());
+        assert!(q1()
+            .fetch_airway("Q2".to_owned(), "AAAAA".to_owned(), "EEEEE".to_owned())
+            .unwrap()
+            .is_empty());
+    }
+
+    fn star_db(legs: &[(Option<&str>, &str)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE tbl_pe_stars (airport_identifier TEXT, procedure_identifier TEXT, transition_identifier TEXT, seqno INTEGER, waypoint_identifier TEXT, waypoint_latitude REAL, waypoint_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        for (i, (transition, id)) in legs.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO tbl_pe_stars VALUES ('EGLL', 'KEPEK1', ?, ?, ?, 51.5, 0.0)",
+                rusqlite::params![transition, (i as i64 + 1) * 10, id],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn star_ids(ir: &InnerRoute, token: &str) -> Vec<String> {
+        ir.fetch_procedure("EGLL".to_owned(), token.to_owned(), 'A', "")
+            .unwrap()
+            .into_iter()
+            .map(|wpt| wpt.id)
+            .collect()
+    }
+
+    #[test]
+    fn star_runway_transition() {
+        let ir = InnerRoute::new(vec![star_db(&[
+            (None, "KEPEK"),
+            (Some("RW09L"), "NINEL"),
+            (Some("RW27R"), "TWSVR"),
+            (Some("RW27L"), "TWSVL"),
+        ])])
+        .unwrap();
+
+        assert_eq!(star_ids(&ir, "KEPEK1/RW27R"), ["KEPEK", "TWSVR"]);
+        assert_eq!(star_ids(&ir, "KEPEK1/27L"), ["KEPEK", "TWSVL"]);
+        assert_eq!(star_ids(&ir, "KEPEK1/RW09L"), ["KEPEK", "NINEL"]);
+    }
+
+    #[test]
+    fn star_both_runways_transition() {
+        let ir = InnerRoute::new(vec![star_db(&[
+            (None, "KEPEK"),
+            (Some("RW09B"), "NINEB"),
+            (Some("RW27B"), "TWSVB"),
+        ])])
+        .unwrap();
+
+        assert_eq!(star_ids(&ir, "KEPEK1/RW27R"), ["KEPEK", "TWSVB"]);
+    }
+
+    /// Legs are `(transition, seqno, waypoint)`, numbered per transition like navigraph does.
+    fn sid_db(legs: &[(Option<&str>, i64, &str)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE tbl_pd_sids (airport_identifier TEXT, procedure_identifier TEXT, transition_identifier TEXT, seqno INTEGER, waypoint_identifier TEXT, waypoint_latitude REAL, waypoint_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        for (transition, seqno, id) in legs {
+            conn.execute(
+                "INSERT INTO tbl_pd_sids VALUES ('EGLL', 'CPT3J', ?, ?, ?, 51.5, 0.0)",
+                rusqlite::params![transition, seqno, id],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn sid_ids(ir: &InnerRoute, token: &str, enroute_fix: &str) -> Vec<String> {
+        ir.fetch_procedure("EGLL".to_owned(), token.to_owned(), 'D', enroute_fix)
+            .unwrap()
+            .into_iter()
+            .map(|wpt| wpt.id)
+            .collect()
+    }
+
+    #[test]
+    fn sid_joins_runway_and_enroute_transitions() {
+        let ir = InnerRoute::new(vec![sid_db(&[
+            (Some("RW27L"), 10, "D254F"),
+            (Some("RW27L"), 20, "WOD"),
+            (Some("RW09R"), 10, "D084A"),
+            (Some("RW09R"), 20, "WOD"),
+            (None, 10, "WOD"),
+            (None, 20, "CPT"),
+            (Some("KENET"), 10, "CPT"),
+            (Some("KENET"), 20, "KENET"),
+            (Some("BAKUR"), 10, "CPT"),
+            (Some("BAKUR"), 20, "BAKUR"),
+        ])])
+        .unwrap();
+
+        assert_eq!(
+            sid_ids(&ir, "CPT3J/27L", "KENET"),
+            ["D254F", "WOD", "CPT", "KENET"]
+        );
+        assert_eq!(
+            sid_ids(&ir, "CPT3J/RW09R", "BAKUR"),
+            ["D084A", "WOD", "CPT", "BAKUR"]
+        );
+        assert_eq!(sid_ids(&ir, "CPT3J/27L", "UMLAT"), ["D254F", "WOD", "CPT"]);
+    }
+
+    fn wp(id: &str, lat: f64, lon: f64) -> Waypoint {
+        Waypoint::new(id.to_owned(), lat, lon)
+    }
+
+    /// One degree of arc on the haversine earth, in nm.
+    const DEGREE_NM: f64 = 60.04;
+
+    #[test]
+    fn closest_segment_on_route() {
+        let route = [wp("A", 0.0, 0.0), wp("B", 0.0, 1.0), wp("C", 0.0, 2.0)];
+        let (from, to, a, b, dev_m) = find_closest_segment(&route, 0.0, 1.5, None).unwrap();
+        assert_eq!((from, to), (1, 2));
+        assert_eq!((a.id.as_str(), b.id.as_str()), ("B", "C"));
+        assert!(dev_m < 1.0);
+    }
+
+    #[test]
+    fn closest_segment_abeam_midpoint() {
+        let route = [wp("A", 0.0, 0.0), wp("B", 0.0, 1.0)];
+        let (from, _, _, _, dev_m) = find_closest_segment(&route, 0.5, 0.5, None).unwrap();
+        assert_eq!(from, 0);
+        assert!((dev_m / 1852.0 - DEGREE_NM / 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn closest_segment_needs_two_waypoints() {
+        assert!(find_closest_segment(&[], 0.0, 0.0, None).is_none());
+        assert!(find_closest_segment(&[wp("A", 0.0, 0.0)], 0.0, 0.0, None).is_none());
+    }
+
+    #[test]
+    fn closest_segment_non_adjacent_leg() {
+        // A U shaped route, the point is next to the last leg and far from the first
+        let route = [
+            wp("A", 0.0, 0.0),
+            wp("B", 0.0, 1.0),
+            wp("C", 1.0, 1.0),
+            wp("D", 1.0, 0.0),
+        ];
+        let (from, _, a, _, dev_m) = find_closest_segment(&route, 0.9, 0.5, None).unwrap();
+        assert_eq!(from, 2);
+        assert_eq!(a.id, "C");
+        assert!((dev_m / 1852.0 - DEGREE_NM / 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn closest_segment_prefers_forward_progress() {
+        let route = [
+            wp("A", 0.0, 0.0),
+            wp("B", 0.0, 1.0),
+            wp("C", 1.0, 1.0),
+            wp("D", 1.0, 0.0),
+        ];
+        // Already on the last leg, a slightly closer earlier leg does not pull us back
+        let (from, ..) = find_closest_segment(&route, 0.45, 0.5, Some(2)).unwrap();
+        assert_eq!(from, 2);
+        // But one much closer does
+        let (from, ..) = find_closest_segment(&route, 0.05, 0.5, Some(2)).unwrap();
+        assert_eq!(from, 0);
+    }
+
+    #[test]
+    fn route_length_in_nm() {
+        let route = [wp("A", 0.0, 0.0), wp("B", 0.0, 1.0), wp("C", 1.0, 1.0)];
+        assert!((route_length_nm(&route) - 2.0 * DEGREE_NM).abs() < 0.1);
+    }
+
+    #[test]
+    fn whole_degree_coordinate() {
+        assert_eq!(parse_coordinate("50N020W"), Some((50.0, -20.0)));
+        assert_eq!(parse_coordinate("45S170E"), Some((-45.0, 170.0)));
+    }
+
+    #[test]
+    fn degrees_minutes_coordinate() {
+        assert_eq!(parse_coordinate("5030N02000W"), Some((50.5, -20.0)));
+        assert_eq!(parse_coordinate("5030N/02030W"), Some((50.5, -20.5)));
+        assert_eq!(parse_coordinate("N5030W02000"), Some((50.5, -20.0)));
+    }
+
+    #[test]
+    fn arinc_coordinate() {
+        assert_eq!(parse_coordinate("5030N"), Some((50.0, -30.0)));
+        assert_eq!(parse_coordinate("N5030"), Some((50.0, -130.0)));
+        assert_eq!(parse_coordinate("H5030"), Some((50.5, -30.0)));
+        assert_eq!(parse_coordinate("ABCDE"), None);
+    }
+
+    #[test]
+    fn slash_separated_coordinate_token() {
+        let ir = InnerRoute::new(vec![waypoint_db(&[])]).unwrap();
+        let mut wps = Vec::new();
+        ir.expand_token(&mut wps, "5030N/02000W", "", None, false)
+            .unwrap();
+        ir.expand_token(&mut wps, "52N030W/N0480F360", "", None, false)
+            .unwrap();
+        assert_eq!(wps.len(), 2);
+        assert_eq!((wps[0].lat, wps[0].lon), (50.5, -20.0));
+        assert_eq!((wps[1].lat, wps[1].lon), (52.0, -30.0));
+    }
+
+    #[test]
+    fn first_fix_disambiguated_by_departure() {
+        let conn = waypoint_db(&[("DUPEX", 50.0, 8.0), ("DUPEX", -33.0, 151.0)]);
+        conn.execute(
+            "CREATE TABLE tbl_pa_airports (airport_identifier TEXT, airport_ref_latitude REAL, airport_ref_longitude REAL)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO tbl_pa_airports VALUES ('YSSY', -33.95, 151.18), ('EDDF', 50.03, 8.56)",
+            [],
+        )
+        .unwrap();
+        let ir = InnerRoute::new(vec![conn]).unwrap();
+
+        for (departure, lat) in [("YSSY", -33.0), ("EDDF", 50.0)] {
+            let flight_plan = FlightPlan {
+                departure: departure.to_owned(),
+                ..Default::default()
+            };
+            let wps = ir
+                .get_waypoints(&["DUPEX".to_owned(), "DCT".to_owned()], &flight_plan)
+                .unwrap();
+            assert_eq!(wps[0].id, "DUPEX");
+            assert_eq!(wps[0].lat, lat);
+        }
+    }
+
+    #[test]
+    fn aircraft_type_and_registration_from_flight_plan() {
+        let plan = |aircraft_short: &str, aircraft: &str, remarks: &str| FlightPlan {
+            aircraft_short: aircraft_short.to_owned(),
+            aircraft: aircraft.to_owned(),
+            remarks: remarks.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(plan("B77W", "", "").aircraft_type(), "B77W");
+        assert_eq!(
+            plan("", "B77W/H-SDE3FGHIJ2J3J4J5M1RWXY/LB1D1", "").aircraft_type(),
+            "B77W"
+        );
+        assert_eq!(plan("", "H/B744/L", "").aircraft_type(), "B744");
+        assert_eq!(
+            plan("", "", "PBN/A1B1 REG/GSTBA OPR/BAW /V/").registration(),
+            Some("GSTBA")
+        );
+        assert_eq!(plan("", "", "/V/").registration(), None);
+    }
+
+    #[test]
+    fn emergency_squawk_codes() {
+        let pilot = |transponder: &str| Pilot {
+            transponder: transponder.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(pilot("7700").emergency_squawk(), Some("general emergency"));
+        assert_eq!(pilot("7600").emergency_squawk(), Some("radio failure"));
+        assert_eq!(pilot("7500").emergency_squawk(), Some("hijack"));
+        assert_eq!(pilot("2000").emergency_squawk(), None);
+        assert_eq!(pilot("").emergency_squawk(), None);
+    }
+
+    #[test]
+    fn check_nav_db_rejects_bad_paths() {
+        let dir = std::env::temp_dir().join(format!("vpilot-alert-navdb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+
+        assert!(check_nav_db(&path("missing.s3db")).is_err());
+        assert!(!dir.join("missing.s3db").exists());
+        assert!(check_nav_db(dir.to_str().unwrap()).is_err());
+
+        std::fs::write(dir.join("text.s3db"), "not a database").unwrap();
+        assert!(check_nav_db(&path("text.s3db")).is_err());
+
+        let conn = Connection::open(path("empty.s3db")).unwrap();
+        conn.execute_batch("CREATE TABLE notes (id INTEGER)")
+            .unwrap();
+        drop(conn);
+        assert!(check_nav_db(&path("empty.s3db")).is_err());
+
+        let conn = Connection::open(path("navdb.s3db")).unwrap();
+        conn.execute_batch("CREATE TABLE tbl_header (version TEXT)")
+            .unwrap();
+        drop(conn);
+        check_nav_db(&path("navdb.s3db")).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn implausible_temperature_falls_back_to_isa() {
+        assert_eq!(checked_temperature(220.0, 250), 220.0);
+        assert!((checked_temperature(-50.0 + 273.15 * 2.0, 250) - 220.8).abs() < 0.1);
+        assert!((checked_temperature(f64::NAN, 500) - 251.9).abs() < 0.1);
+        assert_eq!(checked_temperature(20.0, 200), 216.65);
+    }
+
+    #[test]
+    fn airac_cycle_from_header() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tbl_header (version TEXT, current_airac TEXT);
+             INSERT INTO tbl_header VALUES ('1.0', '2510');",
+        )
+        .unwrap();
+        assert_eq!(NavDb::new(conn).unwrap().cycle.as_deref(), Some("2510"));
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tbl_hdr_header (current_airac INTEGER);
+             INSERT INTO tbl_hdr_header VALUES (2511);",
+        )
+        .unwrap();
+        assert_eq!(NavDb::new(conn).unwrap().cycle.as_deref(), Some("2511"));
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE tbl_header (version TEXT)")
+            .unwrap();
+        assert_eq!(NavDb::new(conn).unwrap().cycle, None);
+    }
+
+    #[test]
+    fn sim_rate_from_track() {
+        // 480kt covers 2nm every 15 seconds
+        let track = |nm_per_poll: f64, ground_speed: i64| {
+            (0..10)
+                .map(|i| TrackPoint {
+                    lat: 50.0 + i as f64 * nm_per_poll / DEGREE_NM,
+                    lon: 0.0,
+                    altitude: 35000,
+                    ground_speed,
+                    epoch_ms: i * 15_000,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(estimate_sim_rate(&track(2.0, 480)), Some(1.0));
+        assert_eq!(estimate_sim_rate(&track(8.0, 480)), Some(4.0));
+        assert_eq!(estimate_sim_rate(&track(1.0, 480)), Some(1.0));
+        assert_eq!(estimate_sim_rate(&track(0.1, 20)), None);
+        assert_eq!(estimate_sim_rate(&track(2.0, 480)[..1]), None);
+    }
+
+    #[test]
+    fn cross_track_side_of_segment() {
+        let (a, b) = (wp("A", 0.0, 0.0), wp("B", 0.0, 1.0));
+        // Flying east, north is on the left
+        assert_eq!(cross_track_side(&a, &b, Point::new(0.5, 0.1)), "left");
+        assert_eq!(cross_track_side(&a, &b, Point::new(0.5, -0.1)), "right");
+        // Flying west it is the other way round
+        assert_eq!(cross_track_side(&b, &a, Point::new(0.5, 0.1)), "right");
+    }
+
+    #[test]
+    fn route_diff_lists_added_and_removed_tokens() {
+        assert_eq!(
+            route_diff(
+                &route_tokens("CPT L9 KENET DCT BAKUR"),
+                &route_tokens("CPT DCT STU DCT BAKUR")
+            ),
+            "+STU -L9 -KENET"
+        );
+        assert_eq!(
+            route_diff(&route_tokens("CPT DCT BAKUR"), &route_tokens("CPT BAKUR")),
+            "-DCT"
+        );
+    }
+
+    /// Run with `cargo test --release -- --ignored get_waypoints_benchmark`, libtest reports the time.
+    #[test]
+    #[ignore]
+    fn get_waypoints_benchmark() {
+        let ids = (0..200).map(|i| format!("F{i:04}")).collect::<Vec<_>>();
+        let fixes = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.as_str(), 50.0, i as f64))
+            .collect::<Vec<_>>();
+        let conn = waypoint_db(&fixes);
+        add_airway(
+            &conn,
+            "UL1",
+            &ids.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        let ir = InnerRoute::new(vec![conn]).unwrap();
+        let route = (0..20)
+            .map(|i| format!("F{:04} UL1 F{:04} F{:04}", i * 10, i * 10 + 5, i * 10 + 7))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let tokens = route_tokens(&route);
+
+        for _ in 0..2000 {
+            // Every pass has to go to the nav db
+            ir.fix_cache.borrow_mut().clear();
+            ir.airway_cache.borrow_mut().clear();
+            ir.get_waypoints(&tokens, &FlightPlan::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn fix_only_in_second_db_is_found() {
+        let ir = InnerRoute::new(vec![
+            waypoint_db(&[("ABCDE", 50.0, 8.0)]),
+            waypoint_db(&[("VWXYZ", 51.5, -0.5)]),
+        ])
+        .unwrap();
+
+        let fixes = ir.get_fix("VWXYZ".to_owned()).unwrap();
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0].lat, 51.5);
+        assert_eq!(fixes[0].lon, -0.5);
+
+        let fixes = ir.get_fix("ABCDE".to_owned()).unwrap();
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0].lat, 50.0);
+    }
+}
@@ -1,38 +1,154 @@
 use std::{
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use chrono::Local;
 use eyre::{Context, Result};
-use serde::Serialize;
-use serde_json::json;
-use tokio::sync::Mutex;
-use tracing::{error, trace};
+use geo::{Distance, Haversine, Point};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, Notify};
+use tracing::{error, trace, warn};
 
 use crate::{
+    events::{Event, EventLog},
     fcm::GoogleServices,
-    route::{Route, RouteStatistics},
+    metrics::Metrics,
+    notify::Notifier,
+    route::{
+        vatsim_poll_interval, NoFlightPlan, Pilot, PilotNotConnected, Route, RouteStatistics,
+        VatsimUnavailable,
+    },
+    store::{StateKey, StateStore},
 };
 
 pub type AppStateType = Arc<Mutex<AppState>>;
+/// Locked separately from the state, so a slow VATSIM poll does not block the API.
+pub type RouteType = Arc<Mutex<Route>>;
+
+/// How many times an unacknowledged alarm is raised again when not auto-disconnecting.
+const MAX_ALARM_RESTARTS: u32 = 3;
+
+/// Deviations smaller than this are noise, even when growing.
+const DEVIATION_TREND_MIN_NM: f64 = 1.0;
 
 pub struct AppState {
-    pub recipient_token: String,
-    pub google_services: GoogleServices,
-    pub notifications: Vec<Notification>,
-    pub callsign: String,
+    pub google_services: Option<GoogleServices>,
+    pub notifiers: Vec<Box<dyn Notifier>>,
+    pub notifications: VecDeque<Notification>,
+    pub notifications_capacity: usize,
+    pub store: StateStore,
+    pub updates: broadcast::Sender<LiveUpdate>,
+    /// Set once the first route statistics of the primary callsign are computed, notifiers log in before serving
+    pub ready: bool,
+    pub callsigns: Vec<String>,
+    pub callsign_aliases: Vec<String>,
     pub vpilot_connected: bool,
     pub alarm: Option<Alarm>,
-    pub stats: RouteStatistics,
-    pub route: Route,
+    pub stats: HashMap<String, RouteStatistics>,
+    pub routes: HashMap<String, RouteType>,
+    /// Wakes the VATSIM poll early, eg. after the route changed
+    pub poll_now: Arc<Notify>,
     pub alert_crashes: bool,
+    pub alarm_grace_period: Duration,
+    /// When each crash condition was first seen, see `persisted_conditions`
+    pub pending_alarms: HashMap<String, Instant>,
+    pub tripped_checks: HashMap<String, Vec<&'static str>>,
+    pub crash_alerted: HashMap<String, HashSet<&'static str>>,
+    pub events: EventLog,
+    pub metrics: Metrics,
+    pub back_on_route_threshold: Option<f64>,
+    pub top_of_descent_notice: Option<f64>,
+    /// Departure and arrival of the flight the top of descent was last notified for
+    pub tod_notified: HashMap<String, (String, String)>,
+    pub waypoint_alerts: Vec<WaypointAlert>,
+    /// Emergency code each callsign was last alerted for
+    pub squawk_alerted: HashMap<String, String>,
+    pub deviation_alerted: HashSet<String>,
+    pub thresholds: CrashThresholds,
+    pub alarm_policy: AlarmPolicy,
+    pub radio_senders: HashMap<String, Instant>,
+    pub radio_debounce: Duration,
+    pub snooze_until: Option<Instant>,
+    pub interface: String,
+    pub alert_types: HashMap<NotificationType, bool>,
+    pub was_on_network: HashSet<String>,
+    pub network_missed_since: HashMap<String, Instant>,
+    pub altitude_samples: HashMap<String, (Instant, i64)>,
+    pub vertical_rates: HashMap<String, f64>,
+    pub progress_samples: HashMap<String, (u64, f64)>,
+    pub progress_reversed: HashSet<String>,
+    /// Route deviations since the last route recompute, up to `deviation_trend_count` + 1 of them
+    pub deviation_samples: HashMap<String, (u64, VecDeque<(Instant, f64)>)>,
+    pub deviation_trend_alerted: HashSet<String>,
+    /// Delay between state loop runs, which re-send alarms and check for crashes
+    pub state_loop_interval: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashThresholds {
+    pub min_altitude: i64,
+    pub altitude_airport_radius_nm: f64,
+    pub max_descent_rate: f64,
+    pub min_ground_speed: i64,
+    pub max_deviation: f64,
+    pub max_progress_reversal: f64,
+    pub stuck_count: usize,
+    pub deviation_trend_count: usize,
+    pub loop_min_segment_nm: f64,
+    pub loop_min_index_gap: usize,
+    pub loop_airport_radius_nm: f64,
+}
+
+/// Notify once when `callsign` comes within `distance_nm` of `fix`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WaypointAlert {
+    pub callsign: String,
+    pub fix: String,
+    pub distance_nm: f64,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlarmPolicy {
+    pub repeat_interval: Duration,
+    pub max_duration: Duration,
+    pub escalate: bool,
+    pub auto_disconnect: bool,
+}
+
+impl AlarmPolicy {
+    /// Time to wait before re-sending an alarm that has been running for `elapsed` out of `timeout`.
+    pub fn repeat_interval(&self, elapsed: Duration, timeout: Duration) -> Duration {
+        if !self.escalate {
+            return self.repeat_interval;
+        }
+
+        // Ring twice as often after a third of the alarm, five times as often after two thirds
+        let divisor = match (elapsed * 3).as_secs_f64() / timeout.as_secs_f64() {
+            x if x < 1.0 => 1,
+            x if x < 2.0 => 2,
+            _ => 5,
+        };
+        (self.repeat_interval / divisor).max(Duration::from_secs(1))
+    }
 }
 
 pub struct Alarm {
     pub started_at: Instant,
     pub last_notified_at: Instant,
     pub alarm_played: bool,
+    pub restarts: u32,
+    pub timeout: Duration,
+    /// Started through the API to check the alarm loop, never escalates past its timeout
+    pub test: bool,
+    pub message: String,
+    pub _type: NotificationType,
 }
 
 impl AppState {
@@ -41,50 +157,619 @@ impl AppState {
         message: String,
         _type: NotificationType,
     ) -> Result<()> {
-        self.google_services
-            .send_fcm_message(&self.recipient_token, json!({ "triggerAlarm": "true" }))
+        if !self.alarm_enabled(&_type) {
+            return self.send_info_notification(message, _type).await;
+        }
+
+        let timeout = self.alarm_policy.max_duration;
+        self.start_alarm(message, _type, timeout, false).await
+    }
+
+    /// Raises an alarm regardless of snoozing, it stops after `timeout` without escalating.
+    pub async fn send_test_alarm(&mut self, timeout: Duration) -> Result<()> {
+        self.start_alarm(
+            "Test alarm".to_string(),
+            NotificationType::PrivateMessage,
+            timeout,
+            true,
+        )
+        .await
+    }
+
+    async fn start_alarm(
+        &mut self,
+        message: String,
+        _type: NotificationType,
+        timeout: Duration,
+        test: bool,
+    ) -> Result<()> {
+        self.notify(&message, &_type, true, false)
             .await
-            .context("Failed to send FCM message")?;
-        self.notifications.push(Notification {
-            message,
-            timestamp: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-            _type,
+            .context("Failed to send notification")?;
+        self.events.record(Event::AlarmStarted {
+            message: &message,
+            _type: &_type,
         });
+        self.push_notification(message.clone(), _type.clone());
 
         let t = Instant::now();
         self.alarm = Some(Alarm {
             started_at: t,
             last_notified_at: t,
             alarm_played: false,
+            restarts: 0,
+            timeout,
+            test,
+            message,
+            _type,
         });
         Ok(())
     }
 
-    pub async fn soft_send_notification(&mut self, message: String, _type: NotificationType) {
-        if self.alarm.is_none() {
-            if let Err(err) = self.send_notification(message, _type).await {
+    pub fn is_snoozed(&self) -> bool {
+        self.snooze_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn alarm_enabled(&self, _type: &NotificationType) -> bool {
+        !self.is_snoozed() && self.alert_types.get(_type).copied().unwrap_or(true)
+    }
+
+    async fn notify(
+        &self,
+        message: &str,
+        _type: &NotificationType,
+        trigger_alarm: bool,
+        repeat: bool,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        for notifier in &self.notifiers {
+            if repeat && !notifier.repeats_alarm() {
+                continue;
+            }
+            if let Err(err) = notifier.notify(message, _type, trigger_alarm).await {
+                error!("Notifier failed: {}", err);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Notify without raising the alarm, the app only lists these.
+    pub async fn send_info_notification(
+        &mut self,
+        message: String,
+        _type: NotificationType,
+    ) -> Result<()> {
+        self.notify(&message, &_type, false, false)
+            .await
+            .context("Failed to send notification")?;
+        self.push_notification(message, _type);
+        Ok(())
+    }
+
+    fn push_notification(&mut self, message: String, _type: NotificationType) {
+        *self
+            .metrics
+            .notifications_sent
+            .entry(_type.clone())
+            .or_default() += 1;
+        let now = Local::now();
+        if self.notifications.len() >= self.notifications_capacity {
+            self.notifications.pop_front();
+        }
+        let notification = Notification {
+            id: Notification::next_id(now.timestamp_millis()),
+            message,
+            timestamp: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            epoch_ms: now.timestamp_millis(),
+            _type,
+            read: false,
+        };
+        // Only fails when no client is listening
+        let _ = self
+            .updates
+            .send(LiveUpdate::Notification(notification.clone()));
+        self.notifications.push_back(notification);
+    }
+
+    /// Replaces the latest notification of this type starting with `prefix`, returns whether one was found.
+    pub fn update_notification(
+        &mut self,
+        prefix: &str,
+        _type: NotificationType,
+        message: String,
+    ) -> bool {
+        let Some(notification) = self
+            .notifications
+            .iter_mut()
+            .rev()
+            .find(|n| n._type == _type && n.message.starts_with(prefix))
+        else {
+            return false;
+        };
+
+        let now = Local::now();
+        notification.message = message;
+        notification.timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
+        notification.epoch_ms = now.timestamp_millis();
+        notification.read = false;
+        true
+    }
+
+    pub fn stop_alarm(&mut self) {
+        if self.alarm.take().is_some() {
+            self.events.record(Event::AlarmStopped {
+                reason: "acknowledged",
+            });
+        }
+    }
+
+    /// Which of the `active` conditions under `prefix` have held for the alarm grace period.
+    ///
+    /// Only for conditions re-evaluated on every state loop with the full set that currently holds:
+    /// those missing from `active` are dropped, which cancels their grace period, so a one-off event
+    /// passed here once would never be sent.
+    fn persisted_conditions(&mut self, prefix: &str, active: &[String]) -> HashSet<String> {
+        self.pending_alarms
+            .retain(|message, _| !message.starts_with(prefix) || active.contains(message));
+        active
+            .iter()
+            .filter(|message| {
+                let first_seen = *self
+                    .pending_alarms
+                    .entry(message.to_string())
+                    .or_insert_with(Instant::now);
+                first_seen.elapsed() >= self.alarm_grace_period
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Persists what would otherwise be lost on shutdown.
+    pub async fn save(&self) -> Result<()> {
+        self.store
+            .save(
+                StateKey::Notifications,
+                &serde_json::to_string(&self.notifications)?,
+            )
+            .await?;
+        if let Some(google_services) = &self.google_services {
+            google_services
+                .save_oauth_token()
+                .await
+                .context("Write OAuth token")?;
+        }
+        Ok(())
+    }
+
+    pub fn primary_callsign(&self) -> &str {
+        &self.callsigns[0]
+    }
+
+    pub fn route(&self, callsign: &str) -> Option<RouteType> {
+        self.routes.get(callsign).cloned()
+    }
+
+    pub fn primary_route(&self) -> RouteType {
+        self.routes[self.primary_callsign()].clone()
+    }
+
+    async fn check_crashes(&mut self, callsign: &str) {
+        let Some(stats) = self.stats.get(callsign) else {
+            return;
+        };
+
+        let mut checks = Vec::new();
+        let mut back_on_route = None;
+        let mut drifting = None;
+        if self.alert_crashes {
+            trace!("{:#?}", stats);
+            if stats.in_loop {
+                checks.push("In loop");
+            }
+
+            if stats.stuck {
+                checks.push("Aircraft stuck");
+            }
+
+            // Climbing out and descending in below the floor is expected
+            if stats.pilot.altitude < self.thresholds.min_altitude
+                && stats.dist_to_airport_nm > self.thresholds.altitude_airport_radius_nm
+            {
+                checks.push("Low altitude");
+            }
+
+            if self
+                .vertical_rates
+                .get(callsign)
+                .is_some_and(|&rate| -rate > self.thresholds.max_descent_rate)
+            {
+                checks.push("Rapid descent");
+            }
+
+            if stats.pilot.ground_speed < self.thresholds.min_ground_speed {
+                checks.push("Low ground speed");
+            }
+
+            if stats.route_deviation > self.thresholds.max_deviation {
+                checks.push("Route deviation");
+            }
+
+            if self.progress_reversed.contains(callsign) {
+                checks.push("Route progress reversed");
+            }
+
+            if let Some(threshold) = self.back_on_route_threshold {
+                if self.deviation_alerted.contains(callsign) && stats.route_deviation < threshold {
+                    back_on_route = Some(stats.route_deviation);
+                }
+            }
+
+            // Below the deviation alarm, a gentle warning while still drifting away
+            if stats.deviation_increasing
+                && stats.route_deviation > DEVIATION_TREND_MIN_NM
+                && stats.route_deviation <= self.thresholds.max_deviation
+            {
+                drifting = Some((stats.route_deviation, stats.deviation_rate));
+            }
+        }
+
+        match drifting {
+            Some((deviation, rate)) if self.deviation_trend_alerted.insert(callsign.to_owned()) => {
+                let message = format!(
+                    "{callsign}: Drifting off route, deviation {deviation:.1}nm growing {rate:.1}nm/min"
+                );
+                if let Err(err) = self
+                    .send_info_notification(message, NotificationType::OffRouteTrend)
+                    .await
+                {
+                    error!("Failed to send notification: {}", err);
+                }
+            }
+            Some(_) => {}
+            None => {
+                self.deviation_trend_alerted.remove(callsign);
+            }
+        }
+
+        if let Some(deviation) = back_on_route {
+            self.deviation_alerted.remove(callsign);
+            let message = format!("{callsign}: Back on route, deviation {deviation:.1}nm");
+            if let Err(err) = self
+                .send_info_notification(message, NotificationType::BackOnRoute)
+                .await
+            {
+                error!("Failed to send notification: {}", err);
+            }
+        }
+
+        let tripped = self.tripped_checks.remove(callsign).unwrap_or_default();
+        for check in &checks {
+            if !tripped.contains(check) {
+                self.events.record(Event::CrashCheck {
+                    callsign,
+                    check,
+                    tripped: true,
+                });
+            }
+        }
+        for check in &tripped {
+            if !checks.contains(check) {
+                self.events.record(Event::CrashCheck {
+                    callsign,
+                    check,
+                    tripped: false,
+                });
+            }
+        }
+        self.tripped_checks
+            .insert(callsign.to_owned(), checks.clone());
+
+        let messages = checks
+            .iter()
+            .map(|check| format!("{callsign}: {check}"))
+            .collect::<Vec<_>>();
+        let persisted = self.persisted_conditions(&format!("{callsign}: "), &messages);
+
+        // Only alarm when a condition newly trips, not again once the alarm is stopped
+        let mut alerted = self.crash_alerted.remove(callsign).unwrap_or_default();
+        alerted.retain(|check| checks.contains(check));
+        if self.alarm.is_none() && self.alarm_enabled(&NotificationType::CrashDetect) {
+            let ready = checks
+                .iter()
+                .zip(&messages)
+                .any(|(check, message)| !alerted.contains(check) && persisted.contains(message));
+
+            // Conditions often trip together, eg. a stall, so one alarm lists all of them
+            if ready {
+                let message = format!("{callsign}: {}", checks.join(", "));
+                match self
+                    .send_notification(message, NotificationType::CrashDetect)
+                    .await
+                {
+                    Ok(()) => {
+                        if checks.contains(&"Route deviation") {
+                            self.deviation_alerted.insert(callsign.to_owned());
+                        }
+                        alerted.extend(checks);
+                    }
+                    Err(err) => error!("Failed to send notification: {}", err),
+                }
+            }
+        }
+        self.crash_alerted.insert(callsign.to_owned(), alerted);
+    }
+
+    /// Whether `pilot` is a new VATSIM position rather than the cached one.
+    fn is_new_position(&self, callsign: &str, pilot: &Pilot) -> bool {
+        self.stats.get(callsign).is_none_or(|stats| {
+            (
+                stats.pilot.latitude,
+                stats.pilot.longitude,
+                stats.pilot.altitude,
+            ) != (pilot.latitude, pilot.longitude, pilot.altitude)
+        })
+    }
+
+    fn sample_altitude(&mut self, callsign: &str, pilot: &Pilot) {
+        let now = Instant::now();
+        if let Some((sampled_at, altitude)) = self
+            .altitude_samples
+            .insert(callsign.to_owned(), (now, pilot.altitude))
+        {
+            let minutes = (now - sampled_at).as_secs_f64() / 60.0;
+            if minutes > 0.0 {
+                self.vertical_rates.insert(
+                    callsign.to_owned(),
+                    (pilot.altitude - altitude) as f64 / minutes,
+                );
+            }
+        }
+    }
+
+    /// Flags progress dropping since the last position, unless the route was recomputed in between.
+    fn sample_progress(&mut self, callsign: &str, progress: f64, route_revision: u64) {
+        let reversed = self
+            .progress_samples
+            .insert(callsign.to_owned(), (route_revision, progress))
+            .is_some_and(|(revision, previous)| {
+                revision == route_revision
+                    && previous - progress > self.thresholds.max_progress_reversal
+            });
+        if reversed {
+            self.progress_reversed.insert(callsign.to_owned());
+        } else {
+            self.progress_reversed.remove(callsign);
+        }
+    }
+
+    /// Notifies once per flight when the top of descent is less than `top_of_descent_notice` nm away.
+    async fn check_top_of_descent(&mut self, callsign: &str) {
+        let (Some(notice), Some(stats)) = (self.top_of_descent_notice, self.stats.get(callsign))
+        else {
+            return;
+        };
+        let Some(flight_plan) = &stats.pilot.flight_plan else {
+            return;
+        };
+        let flight = (flight_plan.departure.clone(), flight_plan.arrival.clone());
+        if stats.dist_to_tod_nm <= 0.0
+            || stats.dist_to_tod_nm > notice
+            || self.tod_notified.get(callsign) == Some(&flight)
+        {
+            return;
+        }
+
+        let message = format!(
+            "{callsign}: Top of descent in {:.0}nm, {:.0}nm remaining to {}",
+            stats.dist_to_tod_nm, stats.dist_remaining_nm, flight.1
+        );
+        self.tod_notified.insert(callsign.to_owned(), flight);
+        if let Err(err) = self
+            .send_info_notification(message, NotificationType::TopOfDescent)
+            .await
+        {
+            error!("Failed to send notification: {}", err);
+        }
+    }
+
+    /// Raises an alarm once per emergency code the pilot squawks.
+    async fn check_squawk(&mut self, callsign: &str) {
+        let Some(stats) = self.stats.get(callsign) else {
+            return;
+        };
+        let Some(meaning) = stats.pilot.emergency_squawk() else {
+            self.squawk_alerted.remove(callsign);
+            return;
+        };
+        let code = stats.pilot.transponder.trim().to_owned();
+        if self.squawk_alerted.get(callsign) == Some(&code) {
+            return;
+        }
+
+        let message = format!("{callsign}: Squawking {code}, {meaning}");
+        self.squawk_alerted.insert(callsign.to_owned(), code);
+        if let Err(err) = self
+            .send_notification(message, NotificationType::EmergencySquawk)
+            .await
+        {
+            error!("Failed to send notification: {}", err);
+        }
+    }
+
+    /// Sends and clears the waypoint alerts this aircraft has come within range of.
+    async fn check_waypoint_alerts(&mut self, callsign: &str) {
+        let Some(stats) = self.stats.get(callsign) else {
+            return;
+        };
+        let position = Point::new(stats.pilot.longitude, stats.pilot.latitude);
+        let distance = |alert: &WaypointAlert| {
+            Haversine.distance(position, Point::new(alert.lon, alert.lat)) / 1852.0
+        };
+        let (reached, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.waypoint_alerts)
+            .into_iter()
+            .partition(|alert| alert.callsign == callsign && distance(alert) <= alert.distance_nm);
+        self.waypoint_alerts = pending;
+
+        for alert in reached {
+            let message = format!("{callsign}: {:.0}nm from {}", distance(&alert), alert.fix);
+            if let Err(err) = self
+                .send_info_notification(message, NotificationType::WaypointApproaching)
+                .await
+            {
+                error!("Failed to send notification: {}", err);
+            }
+        }
+    }
+
+    /// Keeps the deviations needed to spot a trend, starting over when the route is recomputed.
+    fn sample_deviation(&mut self, callsign: &str, deviation: f64, route_revision: u64) {
+        let (revision, samples) = self
+            .deviation_samples
+            .entry(callsign.to_owned())
+            .or_default();
+        if *revision != route_revision {
+            *revision = route_revision;
+            samples.clear();
+        }
+        samples.push_back((Instant::now(), deviation));
+        while samples.len() > self.thresholds.deviation_trend_count + 1 {
+            samples.pop_front();
+        }
+    }
+
+    /// Whether the deviation grew on each of the last `deviation_trend_count` positions, and by how many nm per minute.
+    fn deviation_trend(&self, callsign: &str) -> (bool, f64) {
+        let Some((_, samples)) = self.deviation_samples.get(callsign) else {
+            return (false, 0.0);
+        };
+        let (Some(first), Some(last)) = (samples.front(), samples.back()) else {
+            return (false, 0.0);
+        };
+
+        let minutes = (last.0 - first.0).as_secs_f64() / 60.0;
+        let rate = if minutes > 0.0 {
+            (last.1 - first.1) / minutes
+        } else {
+            0.0
+        };
+        let increasing = samples.len() > self.thresholds.deviation_trend_count
+            && samples
+                .iter()
+                .zip(samples.iter().skip(1))
+                .all(|(a, b)| b.1 > a.1);
+        (increasing, rate)
+    }
+
+    async fn network_seen(&mut self, callsign: &str) {
+        self.network_missed_since.remove(callsign);
+        if self.was_on_network.insert(callsign.to_owned()) {
+            return;
+        }
+
+        if let Err(err) = self
+            .send_info_notification(
+                format!("{callsign}: Reconnected to VATSIM"),
+                NotificationType::NetworkRestored,
+            )
+            .await
+        {
+            error!("Failed to send notification: {}", err);
+        }
+    }
+
+    async fn network_missed(&mut self, callsign: &str) {
+        // The pilot must be missing from two consecutive VATSIM snapshots
+        let missed_since = *self
+            .network_missed_since
+            .entry(callsign.to_owned())
+            .or_insert_with(Instant::now);
+        if missed_since.elapsed() < vatsim_poll_interval() || !self.was_on_network.remove(callsign)
+        {
+            return;
+        }
+
+        error!("{callsign} is no longer connected to vatsim");
+        if let Err(err) = self
+            .send_notification(
+                format!("{callsign}: Lost connection to VATSIM"),
+                NotificationType::NetworkLost,
+            )
+            .await
+        {
+            error!("Failed to send notification: {}", err);
+        }
+    }
+
+    async fn alarm_timed_out(&mut self) {
+        let Some(mut alarm) = self.alarm.take() else {
+            return;
+        };
+
+        if alarm.test {
+            self.events.record(Event::AlarmStopped {
+                reason: "test finished",
+            });
+            return;
+        }
+
+        if !self.alarm_policy.auto_disconnect {
+            if alarm.restarts >= MAX_ALARM_RESTARTS {
+                self.events.record(Event::AlarmStopped {
+                    reason: "unacknowledged",
+                });
+                error!("Alarm was never acknowledged, giving up");
+                return;
+            }
+
+            error!("Alarm time exceeded, raising it again");
+            if let Err(err) = self.notify(&alarm.message, &alarm._type, true, false).await {
                 error!("Failed to send notification: {}", err);
             }
+            let t = Instant::now();
+            alarm.started_at = t;
+            alarm.last_notified_at = t;
+            alarm.alarm_played = false;
+            alarm.restarts += 1;
+            self.alarm = Some(alarm);
+            return;
+        }
+
+        error!("Alarm time exceeded, disconnecting from vatsim");
+        if let Err(err) = self
+            .send_info_notification(
+                format!(
+                    "{}: Alarm not acknowledged, auto-disconnecting",
+                    alarm.message
+                ),
+                alarm._type,
+            )
+            .await
+        {
+            error!("Failed to send notification: {}", err);
         }
+        self.vpilot_connected = false;
+        self.events
+            .record(Event::AlarmStopped { reason: "timeout" });
+        self.events
+            .record(Event::ConnectionChanged { connected: false });
     }
 
     pub async fn state_loop(state: AppStateType) -> Result<()> {
         loop {
             let mut state = state.lock().await;
             if let Some(alarm) = &state.alarm {
-                if alarm.started_at.elapsed() > Duration::from_secs(180) {
-                    state.vpilot_connected = false;
-                    state.alarm = None;
-                    error!("Alarm time exceeded, disconnecting from vatsim");
-                } else if alarm.last_notified_at.elapsed() > Duration::from_secs(10)
+                if alarm.started_at.elapsed() > alarm.timeout {
+                    state.alarm_timed_out().await;
+                } else if alarm.last_notified_at.elapsed()
+                    > state
+                        .alarm_policy
+                        .repeat_interval(alarm.started_at.elapsed(), alarm.timeout)
                     && !alarm.alarm_played
                 {
-                    if let Err(err) = state
-                        .google_services
-                        .send_fcm_message(&state.recipient_token, json!({ "triggerAlarm": "true" }))
-                        .await
-                    {
-                        error!("Failed to send FCM message: {}", err);
+                    if let Err(err) = state.notify(&alarm.message, &alarm._type, true, true).await {
+                        error!("Failed to send notification: {}", err);
                     }
                     if let Some(alarm) = &mut state.alarm {
                         alarm.last_notified_at = Instant::now();
@@ -92,57 +777,191 @@ impl AppState {
                 }
             }
 
-            match state.route.route_statistics().await {
-                Ok(stats) => {
-                    state.stats = stats;
-                }
-                Err(e) => error!("Failed to get route statistics: {}", e),
-            };
-
-            if state.alert_crashes {
-                trace!("{:#?}", state.stats);
-                let mut notifications = Vec::new();
-                if state.stats.in_loop {
-                    notifications.push(("In loop", NotificationType::CrashDetect));
-                }
-
-                if state.stats.stuck {
-                    notifications.push(("Aircraft stuck", NotificationType::CrashDetect));
+            let callsigns = state.callsigns.clone();
+            for callsign in callsigns {
+                if let Some(stats) = state.stats.get(&callsign) {
+                    let _ = state.updates.send(LiveUpdate::Stats {
+                        callsign: callsign.clone(),
+                        stats: Box::new(stats.clone()),
+                    });
                 }
+                state.check_crashes(&callsign).await;
+            }
+            let interval = state.state_loop_interval;
+            drop(state);
+            tokio::time::sleep(interval).await;
+        }
+    }
 
-                if state.stats.pilot.altitude < 29000 {
-                    notifications.push(("Low altitude", NotificationType::CrashDetect));
-                }
+    /// Polls VATSIM and recomputes the route statistics, only holding the state lock to store them.
+    pub async fn vatsim_loop(state: AppStateType) -> Result<()> {
+        loop {
+            let poll_now = state.lock().await.poll_now.clone();
+            tokio::select! {
+                _ = tokio::time::sleep(vatsim_poll_interval()) => {}
+                _ = poll_now.notified() => {}
+            }
 
-                if state.stats.pilot.ground_speed < 300 {
-                    notifications.push(("Low ground speed", NotificationType::CrashDetect));
-                }
+            let callsigns = state.lock().await.callsigns.clone();
+            for callsign in callsigns {
+                let route = state.lock().await.route(&callsign).unwrap();
+                let (result, revision, amendment) = {
+                    let mut route = route.lock().await;
+                    let result = route.route_statistics().await;
+                    (result, route.route_revision(), route.take_route_amendment())
+                };
 
-                if state.stats.route_deviation > 30.0 {
-                    notifications.push(("Route deviation", NotificationType::CrashDetect));
-                }
-                for n in notifications {
-                    state.soft_send_notification(n.0.to_owned(), n.1).await
-                }
+                let mut state = state.lock().await;
+                match result {
+                    Ok(mut stats) => {
+                        if state.is_new_position(&callsign, &stats.pilot) {
+                            state.sample_altitude(&callsign, &stats.pilot);
+                            state.sample_progress(&callsign, stats.route_progress, revision);
+                            state.sample_deviation(&callsign, stats.route_deviation, revision);
+                        }
+                        (stats.deviation_increasing, stats.deviation_rate) =
+                            state.deviation_trend(&callsign);
+                        state.stats.insert(callsign.clone(), stats);
+                        if callsign == state.primary_callsign() {
+                            state.ready = true;
+                        }
+                        state.network_seen(&callsign).await;
+                        state.check_squawk(&callsign).await;
+                        state.check_top_of_descent(&callsign).await;
+                        state.check_waypoint_alerts(&callsign).await;
+                        // A one-off event, so no grace period, and never raises an alarm of its own
+                        if let Some(diff) = amendment {
+                            if let Err(err) = state
+                                .send_info_notification(
+                                    format!("{callsign}: Route amended, recomputing ({diff})"),
+                                    NotificationType::RouteAmended,
+                                )
+                                .await
+                            {
+                                error!("Failed to send notification: {}", err);
+                            }
+                        }
+                    }
+                    Err(e) if e.is::<PilotNotConnected>() => {
+                        state.network_missed(&callsign).await;
+                    }
+                    // Not an error before filing, the route logs it once
+                    Err(e) if e.is::<NoFlightPlan>() => state.network_seen(&callsign).await,
+                    // Every callsign sees the failure until the next poll, only the first fetched it
+                    Err(e)
+                        if e.downcast_ref::<VatsimUnavailable>()
+                            .is_some_and(VatsimUnavailable::is_cached) => {}
+                    // Keep the last stats, the fetch is retried on the next poll
+                    Err(e) if e.is::<VatsimUnavailable>() => {
+                        state.metrics.vatsim_fetch_failures += 1;
+                        warn!("{}", e);
+                    }
+                    Err(e) => error!("Failed to get route statistics for {callsign}: {}", e),
+                };
             }
-            drop(state);
-            tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum NotificationType {
     PrivateMessage,
     RadioMessage,
     SelcalAlert,
     CrashDetect,
+    BackOnRoute,
+    NetworkLost,
+    NetworkRestored,
+    RouteAmended,
+    OffRouteTrend,
+    TopOfDescent,
+    WaypointApproaching,
+    EmergencySquawk,
+}
+
+impl NotificationType {
+    pub const ALL: [NotificationType; 12] = [
+        NotificationType::PrivateMessage,
+        NotificationType::RadioMessage,
+        NotificationType::SelcalAlert,
+        NotificationType::CrashDetect,
+        NotificationType::BackOnRoute,
+        NotificationType::NetworkLost,
+        NotificationType::NetworkRestored,
+        NotificationType::RouteAmended,
+        NotificationType::OffRouteTrend,
+        NotificationType::TopOfDescent,
+        NotificationType::WaypointApproaching,
+        NotificationType::EmergencySquawk,
+    ];
+
+    /// Lets the app pick a ringtone, from `low` to `critical`.
+    pub fn severity(&self) -> &'static str {
+        match self {
+            NotificationType::BackOnRoute
+            | NotificationType::NetworkRestored
+            | NotificationType::RouteAmended
+            | NotificationType::OffRouteTrend
+            | NotificationType::TopOfDescent => "low",
+            NotificationType::PrivateMessage
+            | NotificationType::RadioMessage
+            | NotificationType::WaypointApproaching => "normal",
+            NotificationType::SelcalAlert | NotificationType::NetworkLost => "high",
+            NotificationType::CrashDetect | NotificationType::EmergencySquawk => "critical",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            NotificationType::PrivateMessage => "Private Message",
+            NotificationType::RadioMessage => "Radio Message",
+            NotificationType::SelcalAlert => "SELCAL",
+            NotificationType::CrashDetect => "Crash Detected",
+            NotificationType::BackOnRoute => "Back On Route",
+            NotificationType::NetworkLost => "VATSIM Disconnect",
+            NotificationType::NetworkRestored => "VATSIM Reconnect",
+            NotificationType::RouteAmended => "Route Amended",
+            NotificationType::OffRouteTrend => "Drifting Off Route",
+            NotificationType::TopOfDescent => "Top Of Descent",
+            NotificationType::WaypointApproaching => "Approaching Waypoint",
+            NotificationType::EmergencySquawk => "Emergency Squawk",
+        }
+    }
 }
 
+/// Pushed to websocket clients as it happens.
 #[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LiveUpdate {
+    Stats {
+        callsign: String,
+        stats: Box<RouteStatistics>,
+    },
+    Notification(Notification),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Notification {
+    /// Stays the same when the notification is updated or others are removed
+    #[serde(default)]
+    pub id: String,
     pub message: String,
     pub timestamp: String,
+    pub epoch_ms: i64,
     #[serde(rename = "type")]
     pub _type: NotificationType,
+    #[serde(default)]
+    pub read: bool,
+}
+
+static NOTIFICATION_SEQ: AtomicU64 = AtomicU64::new(0);
+
+impl Notification {
+    /// The sequence number keeps notifications created in the same millisecond apart.
+    pub fn next_id(epoch_ms: i64) -> String {
+        format!(
+            "{epoch_ms}-{}",
+            NOTIFICATION_SEQ.fetch_add(1, Ordering::Relaxed)
+        )
+    }
 }
@@ -0,0 +1,111 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use eyre::{Context, Result};
+use rusqlite::{Connection, OptionalExtension};
+
+/// What is kept between runs.
+#[derive(Debug, Clone, Copy)]
+pub enum StateKey {
+    Token,
+    Notifications,
+    Loops,
+}
+
+impl StateKey {
+    fn name(self) -> &'static str {
+        match self {
+            StateKey::Token => "token",
+            StateKey::Notifications => "notifications",
+            StateKey::Loops => "loops",
+        }
+    }
+}
+
+/// Persists the state either as files next to the token, or in one SQLite database.
+#[derive(Clone)]
+pub enum StateStore {
+    Files { token_path: PathBuf },
+    Sqlite(Arc<Mutex<Connection>>),
+}
+
+impl StateStore {
+    pub fn files(token_path: &Path) -> StateStore {
+        StateStore::Files {
+            token_path: token_path.to_owned(),
+        }
+    }
+
+    pub fn sqlite(path: &Path) -> Result<StateStore> {
+        let conn = Connection::open(path).context("Open state db")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )
+        .context("Create state table")?;
+        Ok(StateStore::Sqlite(Arc::new(Mutex::new(conn))))
+    }
+
+    fn path(token_path: &Path, key: StateKey) -> PathBuf {
+        let dir = token_path.parent().unwrap_or(Path::new(""));
+        match key {
+            StateKey::Token => token_path.to_owned(),
+            StateKey::Notifications => dir.join("notifications.json"),
+            StateKey::Loops => dir.join("loops.json"),
+        }
+    }
+
+    /// `None` when nothing was saved under `key` yet.
+    pub async fn load(&self, key: StateKey) -> Result<Option<String>> {
+        match self {
+            StateStore::Files { token_path } => {
+                match tokio::fs::read_to_string(Self::path(token_path, key)).await {
+                    Ok(value) => Ok(Some(value)),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(err) => Err(err).context(format!("Read {}", key.name())),
+                }
+            }
+            StateStore::Sqlite(conn) => {
+                let conn = conn.clone();
+                tokio::task::spawn_blocking(move || {
+                    conn.lock()
+                        .unwrap()
+                        .query_row(
+                            "SELECT value FROM state WHERE key = ?",
+                            [key.name()],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                })
+                .await?
+                .context(format!("Read {}", key.name()))
+            }
+        }
+    }
+
+    pub async fn save(&self, key: StateKey, value: &str) -> Result<()> {
+        match self {
+            StateStore::Files { token_path } => {
+                tokio::fs::write(Self::path(token_path, key), value)
+                    .await
+                    .context(format!("Write {}", key.name()))
+            }
+            StateStore::Sqlite(conn) => {
+                let conn = conn.clone();
+                let value = value.to_owned();
+                tokio::task::spawn_blocking(move || {
+                    conn.lock().unwrap().execute(
+                        "INSERT INTO state (key, value) VALUES (?1, ?2)
+                         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                        [key.name(), &value],
+                    )
+                })
+                .await?
+                .map(|_| ())
+                .context(format!("Write {}", key.name()))
+            }
+        }
+    }
+}